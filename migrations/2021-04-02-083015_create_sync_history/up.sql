@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE sync_history (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	"source" TEXT NOT NULL,
+	"started_at" TEXT NOT NULL,
+	"finished_at" TEXT,
+	"commit_before" TEXT,
+	"commit_after" TEXT,
+	"crates_changed" INTEGER,
+	"error" TEXT
+);
\ No newline at end of file
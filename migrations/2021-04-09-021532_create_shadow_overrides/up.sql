@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE shadow_overrides (
+	"name" TEXT PRIMARY KEY NOT NULL,
+	"allowed_by" TEXT NOT NULL,
+	"created_at" TEXT NOT NULL
+);
\ No newline at end of file
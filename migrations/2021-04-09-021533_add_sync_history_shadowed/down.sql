@@ -0,0 +1,17 @@
+-- This file should undo anything in `up.sql`
+-- DROP COLUMN needs SQLite 3.35, rebuild the table without it
+CREATE TABLE sync_history_old (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	"source" TEXT NOT NULL,
+	"started_at" TEXT NOT NULL,
+	"finished_at" TEXT,
+	"commit_before" TEXT,
+	"commit_after" TEXT,
+	"crates_changed" INTEGER,
+	"error" TEXT
+);
+INSERT INTO sync_history_old
+	SELECT "id", "source", "started_at", "finished_at", "commit_before", "commit_after", "crates_changed", "error"
+	FROM sync_history;
+DROP TABLE sync_history;
+ALTER TABLE sync_history_old RENAME TO sync_history;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE sync_history ADD COLUMN "shadowed" TEXT;
\ No newline at end of file
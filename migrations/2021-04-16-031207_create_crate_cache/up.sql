@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE crate_cache (
+	"key" TEXT PRIMARY KEY NOT NULL,
+	"size" BIGINT NOT NULL,
+	"last_access" TIMESTAMP NOT NULL
+);
+CREATE INDEX crate_cache_last_access ON crate_cache ("last_access");
\ No newline at end of file
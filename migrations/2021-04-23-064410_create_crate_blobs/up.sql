@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE crate_blobs (
+	"key" TEXT PRIMARY KEY NOT NULL,
+	"cksum" TEXT NOT NULL,
+	"size" BIGINT NOT NULL,
+	"created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX crate_blobs_cksum ON crate_blobs ("cksum");
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE version_downloads (
+	"name" TEXT NOT NULL,
+	"version" TEXT NOT NULL,
+	"date" DATE NOT NULL,
+	"downloads" INTEGER NOT NULL DEFAULT 0,
+	PRIMARY KEY ("name", "version", "date")
+);
+CREATE INDEX version_downloads_date ON version_downloads ("date");
\ No newline at end of file
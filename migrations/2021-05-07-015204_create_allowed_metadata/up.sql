@@ -0,0 +1,22 @@
+-- Your SQL goes here
+CREATE TABLE allowed_metadata (
+	"kind" TEXT NOT NULL,
+	"value" TEXT NOT NULL,
+	"allowed_by" TEXT NOT NULL,
+	"created_at" TEXT NOT NULL,
+	PRIMARY KEY ("kind", "value")
+);
+-- the badge types crates.io knows
+INSERT INTO allowed_metadata ("kind", "value", "allowed_by", "created_at") VALUES
+	('badges', 'appveyor', 'default', datetime('now', 'localtime')),
+	('badges', 'azure-devops', 'default', datetime('now', 'localtime')),
+	('badges', 'bitbucket-pipelines', 'default', datetime('now', 'localtime')),
+	('badges', 'circle-ci', 'default', datetime('now', 'localtime')),
+	('badges', 'cirrus-ci', 'default', datetime('now', 'localtime')),
+	('badges', 'codecov', 'default', datetime('now', 'localtime')),
+	('badges', 'coveralls', 'default', datetime('now', 'localtime')),
+	('badges', 'gitlab', 'default', datetime('now', 'localtime')),
+	('badges', 'is-it-maintained-issue-resolution', 'default', datetime('now', 'localtime')),
+	('badges', 'is-it-maintained-open-issues', 'default', datetime('now', 'localtime')),
+	('badges', 'maintenance', 'default', datetime('now', 'localtime')),
+	('badges', 'travis-ci', 'default', datetime('now', 'localtime'));
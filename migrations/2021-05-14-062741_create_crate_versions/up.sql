@@ -0,0 +1,23 @@
+-- Your SQL goes here
+CREATE TABLE crate_versions (
+	"name" TEXT NOT NULL,
+	"version" TEXT NOT NULL,
+	"description" TEXT,
+	"license" TEXT,
+	"license_file" TEXT,
+	"authors" TEXT NOT NULL,
+	"homepage" TEXT,
+	"documentation" TEXT,
+	"repository" TEXT,
+	"readme" TEXT,
+	"readme_file" TEXT,
+	"keywords" TEXT NOT NULL,
+	"categories" TEXT NOT NULL,
+	"features" TEXT NOT NULL,
+	"deps" TEXT NOT NULL,
+	"links" TEXT,
+	"crate_size" BIGINT NOT NULL,
+	"published_by" TEXT NOT NULL,
+	"created_at" TEXT NOT NULL,
+	PRIMARY KEY ("name", "version")
+);
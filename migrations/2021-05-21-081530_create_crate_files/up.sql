@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE crate_files (
+	"name" TEXT NOT NULL,
+	"version" TEXT NOT NULL,
+	"path" TEXT NOT NULL,
+	"size" BIGINT NOT NULL,
+	PRIMARY KEY ("name", "version", "path")
+);
\ No newline at end of file
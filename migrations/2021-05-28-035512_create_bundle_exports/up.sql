@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE bundle_exports (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	"commit" TEXT NOT NULL,
+	"crates" TEXT NOT NULL,
+	"created_at" TEXT NOT NULL
+);
\ No newline at end of file
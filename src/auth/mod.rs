@@ -307,6 +307,47 @@ async fn who(id: Identity, data: web::Data<Server>) -> Result<impl Responder> {
     }
 }
 
+/// when `registry.auth_required` is on, the index and crate downloads need a valid token.
+/// cargo sends the token as is, git sends it as the password of basic authorization.
+/// returns the user of the token, none when the authorization is not required.
+pub(crate) async fn check_registry_token(
+    req: &HttpRequest,
+    data: &web::Data<Server>,
+) -> Result<Option<String>> {
+    if !data.config.read().await.registry.auth_required {
+        return Ok(None);
+    }
+
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| match h.strip_prefix("Basic ") {
+            Some(basic) => base64::decode(basic.trim())
+                .ok()
+                .and_then(|b| String::from_utf8(b).ok())
+                .and_then(|b| b.splitn(2, ':').nth(1).map(|t| t.to_string()))
+                .unwrap_or_default(),
+            None => h.to_string(),
+        });
+
+    let msg = match token {
+        Some(token) => match get_user_by_token(&*data.database.lock().await, &token) {
+            Ok(account) => return Ok(Some(account.username)),
+            Err(e) => format!("{:?}", e),
+        },
+        None => "need token for authorization".to_string(),
+    };
+
+    Err(error::InternalError::from_response(
+        msg.clone(),
+        HttpResponse::Unauthorized()
+            .append_header(("WWW-Authenticate", r#"Basic realm="mirror-registry""#))
+            .body(msg),
+    )
+    .into())
+}
+
 pub(crate) fn check(id: &Identity, db: &Database) -> Result<Account> {
     if let Some(id) = id.identity() {
         return Ok(get_user_by_name(db, id)
@@ -36,6 +36,76 @@ pub struct Crates {
     pub storage_path: PathBuf,
     /// update crates url, default is https://crates.io
     pub upstream_url: String,
+    /// the crate files are downloaded from these in order, the next one is tried when one
+    /// fails or is down, `upstream_url` is the only one if empty
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    /// store the crate files in a s3-compatible object storage instead of `storage_path`
+    #[serde(default)]
+    pub s3: Option<S3>,
+    /// the cached upstream crates are evicted least recently used first when they take more
+    /// than this, in MB, no limit if not set
+    #[serde(default)]
+    pub cache_quota_mb: Option<u64>,
+    /// the cached upstream crates not downloaded for this many days are evicted
+    #[serde(default)]
+    pub cache_max_age_days: Option<u32>,
+    /// the upstream crate files failed verification repeatedly are kept here for inspection,
+    /// default is ~/.mirror/quarantine
+    #[serde(default = "default_quarantine_path")]
+    pub quarantine_path: PathBuf,
+    /// the largest metadata of a publish in KB, default is 1024
+    #[serde(default = "default_max_metadata_kb")]
+    pub max_metadata_kb: u64,
+    /// the largest crate file of a publish in MB, default is 10
+    #[serde(default = "default_max_crate_mb")]
+    pub max_crate_mb: u64,
+}
+
+fn default_max_metadata_kb() -> u64 {
+    1024
+}
+
+fn default_max_crate_mb() -> u64 {
+    10
+}
+
+/// a crate download source, eg. a secondary mirror or a peer registry
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Source {
+    /// the same as `upstream_url`, or a template with `{crate}` and `{version}` in it,
+    /// eg. https://static.crates.io/crates/{crate}/{crate}-{version}.crate
+    pub url: String,
+    /// the timeout of a download in seconds, default is 30
+    #[serde(default = "default_source_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_source_timeout() -> u64 {
+    30
+}
+
+fn default_quarantine_path() -> PathBuf {
+    Path::new(&env::var("HOME").unwrap()).join(".mirror/quarantine")
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct S3 {
+    /// eg. http://127.0.0.1:9000 for a local MinIO
+    pub endpoint: String,
+    pub bucket: String,
+    /// default is us-east-1
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// key prefix of the crate files, eg. `crates/`
+    #[serde(default)]
+    pub prefix: String,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
 }
 
 #[derive(Serialize, Deserialize)]
@@ -46,6 +116,15 @@ pub struct Registry {
     pub address: String,
     /// sync interval, default is 6 hours
     pub interval: std::time::Duration,
+    /// index and crate downloads need a valid token, default is false
+    #[serde(default)]
+    pub auth_required: bool,
+    /// token of the sync webhook, the webhook is disabled if not set
+    #[serde(default)]
+    pub webhook_token: Option<String>,
+    /// no network to upstream, the index and crates are imported from bundles, default is false
+    #[serde(default)]
+    pub offline: bool,
     /// ldap config
     pub ldap: Option<Ldap>,
 }
@@ -88,6 +167,21 @@ pub struct Git {
     pub working_path: PathBuf,
     /// upstream index uri, default is https://github.com/rust-lang/crates.io-index
     pub upstream_url: String,
+    /// git-http-backend path, if set, git requests are forwarded to this CGI
+    /// instead of the built-in upload-pack server
+    #[serde(default)]
+    pub http_backend: Option<PathBuf>,
+    /// upstream sparse index url, eg. https://index.crates.io, if set, the upstream index
+    /// is fetched on demand instead of cloning the whole git index
+    #[serde(default)]
+    pub upstream_sparse_url: Option<String>,
+    /// cache path of the upstream sparse index files, default is ~/.mirror/sparse-cache
+    #[serde(default = "default_sparse_cache_path")]
+    pub sparse_cache_path: PathBuf,
+}
+
+fn default_sparse_cache_path() -> PathBuf {
+    Path::new(&env::var("HOME").unwrap()).join(".mirror/sparse-cache")
 }
 
 impl Default for Config {
@@ -110,14 +204,27 @@ impl Default for Config {
                 index_path: home_path.join(".mirror/index.git"),
                 working_path: home_path.join(".mirror/work.git"),
                 upstream_url: CRATES_IO_INDEX_URL.to_string(),
+                http_backend: None,
+                upstream_sparse_url: None,
+                sparse_cache_path: home_path.join(".mirror/sparse-cache"),
             },
             crates: Crates {
                 storage_path: home_path.join(".mirror/crates"),
                 upstream_url: CREATE_IO_URL.to_string(),
+                sources: Vec::new(),
+                s3: None,
+                cache_quota_mb: None,
+                cache_max_age_days: None,
+                quarantine_path: home_path.join(".mirror/quarantine"),
+                max_metadata_kb: default_max_metadata_kb(),
+                max_crate_mb: default_max_crate_mb(),
             },
             registry: Registry {
                 address: format!("http://{}", local_ip.unwrap()),
                 interval: Duration::hours(6).to_std().unwrap(),
+                auth_required: false,
+                webhook_token: None,
+                offline: false,
                 can_create_account: true,
                 ldap: None,
             },
@@ -226,6 +333,25 @@ fn modify_configs(config: &mut Config, value: &Value) -> anyhow::Result<()> {
         if let Some(upstream_url) = git_cfg["upstream_url"].as_str() {
             config.git.upstream_url = upstream_url.to_string();
         }
+
+        if let Some(http_backend) = git_cfg.get("http_backend") {
+            config.git.http_backend = http_backend
+                .as_str()
+                .filter(|s| !s.is_empty())
+                .map(PathBuf::from);
+        }
+
+        if let Some(upstream_sparse_url) = git_cfg.get("upstream_sparse_url") {
+            config.git.upstream_sparse_url = upstream_sparse_url
+                .as_str()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string());
+        }
+
+        if let Some(sparse_cache_path) = git_cfg.get("sparse_cache_path").and_then(|p| p.as_str()) {
+            check_and_move(&config.git.sparse_cache_path, sparse_cache_path)?;
+            config.git.sparse_cache_path = PathBuf::from(sparse_cache_path);
+        }
     }
 
     if let Some(crates_cfg) = value["crates"].as_object() {
@@ -237,6 +363,45 @@ fn modify_configs(config: &mut Config, value: &Value) -> anyhow::Result<()> {
         if let Some(upstream_url) = crates_cfg["upstream_url"].as_str() {
             config.crates.upstream_url = upstream_url.to_string();
         }
+
+        if let Some(sources) = crates_cfg.get("sources") {
+            config.crates.sources = if sources.is_null() {
+                Vec::new()
+            } else {
+                serde_json::from_value(sources.clone()).context("invalid crates sources")?
+            };
+        }
+
+        if let Some(quarantine_path) = crates_cfg.get("quarantine_path").and_then(|p| p.as_str()) {
+            // nothing is quarantined yet if it does not exist
+            if config.crates.quarantine_path.exists() {
+                check_and_move(&config.crates.quarantine_path, quarantine_path)?;
+            }
+            config.crates.quarantine_path = PathBuf::from(quarantine_path);
+        }
+
+        // 0 or null turns it off
+        if let Some(quota) = crates_cfg.get("cache_quota_mb") {
+            config.crates.cache_quota_mb = quota.as_u64().filter(|q| *q > 0);
+        }
+
+        if let Some(kb) = crates_cfg.get("max_metadata_kb").and_then(|k| k.as_u64()) {
+            if kb == 0 {
+                return Err(anyhow!("max_metadata_kb can not be 0"));
+            }
+            config.crates.max_metadata_kb = kb;
+        }
+
+        if let Some(mb) = crates_cfg.get("max_crate_mb").and_then(|m| m.as_u64()) {
+            if mb == 0 {
+                return Err(anyhow!("max_crate_mb can not be 0"));
+            }
+            config.crates.max_crate_mb = mb;
+        }
+
+        if let Some(days) = crates_cfg.get("cache_max_age_days") {
+            config.crates.cache_max_age_days = days.as_u64().filter(|d| *d > 0).map(|d| d as u32);
+        }
     }
 
     if let Some(db_cfg) = value["database"].as_object() {
@@ -263,6 +428,21 @@ fn modify_configs(config: &mut Config, value: &Value) -> anyhow::Result<()> {
             config.registry.interval = interval.to_std()?;
         }
 
+        if let Some(ar) = reg_cfg["auth_required"].as_bool() {
+            config.registry.auth_required = ar;
+        }
+
+        if let Some(webhook_token) = reg_cfg.get("webhook_token") {
+            config.registry.webhook_token = webhook_token
+                .as_str()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string());
+        }
+
+        if let Some(offline) = reg_cfg.get("offline").and_then(|o| o.as_bool()) {
+            config.registry.offline = offline;
+        }
+
         if let Some(cca) = reg_cfg["can_create_account"].as_bool() {
             config.registry.can_create_account = cca;
         }
@@ -300,9 +480,25 @@ pub async fn set_config(
     }
 
     let value = value.into_inner();
-    let mut config = data.config.write().await;
-    modify_configs(&mut config, &value)
-        .map_err(|e| ErrorInternalServerError(format!("set config failed: {:?}", e)))?;
+    let auth_required_changed = {
+        let mut config = data.config.write().await;
+        let auth_required = config.registry.auth_required;
+        modify_configs(&mut config, &value)
+            .map_err(|e| ErrorInternalServerError(format!("set config failed: {:?}", e)))?;
+        auth_required != config.registry.auth_required
+    };
+
+    // auth-required lives in the config.json of index, let cargo know it
+    if auth_required_changed && *data.git.inited.lock().await {
+        data.git
+            .modify_config_json()
+            .await
+            .map_err(|e| ErrorInternalServerError(format!("update config.json failed: {:?}", e)))?;
+        data.git
+            .sync_index()
+            .await
+            .map_err(|e| ErrorInternalServerError(format!("sync index failed: {:?}", e)))?;
+    }
 
     Ok(HttpResponse::Ok())
 }
@@ -0,0 +1,503 @@
+use super::{
+    models::IndexMetadata,
+    prefetch::{self, Target},
+    verify::crate_file_version,
+};
+use crate::{
+    database::{
+        schema::{bundle_exports, crates},
+        Database,
+    },
+    storage::crate_key,
+    Server,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Local;
+use diesel::prelude::*;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task;
+
+#[cfg(test)]
+mod test {
+    use super::{conflicts, is_conflict, merge_rows, split_key, CrateRow};
+    use crate::database::{schema::crates, Database};
+    use diesel::prelude::*;
+
+    fn row(name: &str, owners: &str, downloads: i32) -> CrateRow {
+        CrateRow {
+            id: name.to_string(),
+            name: name.to_string(),
+            updated_at: String::new(),
+            versions: None,
+            keywords: None,
+            categories: None,
+            created_at: String::new(),
+            downloads,
+            recent_downloads: 0,
+            max_version: "1.0.0".to_string(),
+            newest_version: "1.0.0".to_string(),
+            max_stable_version: None,
+            description: None,
+            homepage: None,
+            documentation: None,
+            repository: None,
+            owners: Some(owners.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_import_conflicting_owner() {
+        let db = Database::memory();
+        let local = vec![row("foo", "alice", 1), row("baz", "dave", 1)];
+        merge_rows(&db.connection, local, &[]).unwrap();
+
+        let bundled = vec![
+            row("foo", "bob", 9),
+            row("bar", "carol", 2),
+            row("baz", "dave", 3),
+        ];
+        let conflicts = conflicts(&db.connection, &bundled).unwrap();
+        assert_eq!(conflicts, vec!["foo".to_string()]);
+
+        let keys = ["foo/foo-1.0.0.crate", "bar/bar-1.0.0.crate"];
+        let imported = keys
+            .iter()
+            .filter(|k| !is_conflict(&conflicts, split_key(k).unwrap().0))
+            .collect::<Vec<_>>();
+        assert_eq!(imported, vec![&"bar/bar-1.0.0.crate"]);
+
+        merge_rows(&db.connection, bundled, &conflicts).unwrap();
+        let rows = crates::table
+            .order(crates::id.asc())
+            .select((crates::id, crates::owners, crates::downloads))
+            .load::<(String, Option<String>, i32)>(&db.connection)
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("bar".to_string(), Some("carol".to_string()), 2),
+                ("baz".to_string(), Some("dave".to_string()), 3),
+                ("foo".to_string(), Some("alice".to_string()), 1),
+            ]
+        );
+    }
+}
+
+const MANIFEST: &str = "manifest.json";
+const INDEX_BUNDLE: &str = "index.bundle";
+const PRIVATE_CRATES: &str = "private-crates.json";
+const CRATES_DIR: &str = "crates";
+
+/// what is in a bundle
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    created_at: String,
+    /// the index commit of this bundle, pass it to `export --since` for the next one
+    commit: String,
+    /// the index commit this bundle is based on, `None` for a full bundle
+    base: Option<String>,
+    /// whether there are index commits in this bundle
+    has_index: bool,
+    /// the keys of the crate files
+    crates: Vec<String>,
+    private_crates: usize,
+    /// the private crates kept as they are on import, their owners here are not the bundled ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    conflicts: Vec<String>,
+    /// the bundled versions not imported as `name-version`, the index here has them different
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dropped: Vec<String>,
+}
+
+/// a whole row of the `crates` table, `models::Crates` skips most of them in json
+#[derive(Queryable, Insertable, Serialize, Deserialize)]
+#[table_name = "crates"]
+struct CrateRow {
+    id: String,
+    name: String,
+    updated_at: String,
+    versions: Option<String>,
+    keywords: Option<String>,
+    categories: Option<String>,
+    created_at: String,
+    downloads: i32,
+    recent_downloads: i32,
+    max_version: String,
+    newest_version: String,
+    max_stable_version: Option<String>,
+    description: Option<String>,
+    homepage: Option<String>,
+    documentation: Option<String>,
+    repository: Option<String>,
+    owners: Option<String>,
+}
+
+/// the crate files packed into a bundle made here, the incremental bundles leave them out
+#[derive(Insertable)]
+#[table_name = "bundle_exports"]
+struct NewExport<'a> {
+    commit: &'a str,
+    /// the keys in json
+    crates: String,
+    created_at: String,
+}
+
+/// the keys of the crate files packed into the bundles made here, up to the last one made at
+/// `since`, so the keys not packed yet are packed no matter when they are stored
+fn exported_keys(db: &Database, since: &str) -> Result<HashSet<String>> {
+    let last: Option<i32> = bundle_exports::table
+        .filter(bundle_exports::commit.eq(since))
+        .select(bundle_exports::id)
+        .order(bundle_exports::id.desc())
+        .first(&db.connection)
+        .optional()?;
+    let last = last.ok_or(anyhow!(
+        "no bundle is made at {} here, export without --since for a full one",
+        since
+    ))?;
+
+    let mut keys = HashSet::new();
+    for crates in bundle_exports::table
+        .filter(bundle_exports::id.le(last))
+        .select(bundle_exports::crates)
+        .load::<String>(&db.connection)?
+    {
+        keys.extend(serde_json::from_str::<Vec<String>>(&crates)?);
+    }
+
+    Ok(keys)
+}
+
+/// a scratch directory removed on drop
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new() -> Result<Self> {
+        let dir = env::temp_dir().join(format!("mirror-bundle-{:x}", rand::random::<u32>()));
+        fs::create_dir_all(&dir)?;
+        Ok(ScratchDir(dir))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            warn!("remove {:?} failed: {:?}", self.0, e);
+        }
+    }
+}
+
+/// pack the index, the crate files of `targets`, the private crates and their files into
+/// `output`. with `since`, only the index commits after it and the crate files not in the
+/// bundles made here up to it are packed. with `cached`, every crate file in storage is packed.
+pub(crate) async fn export(
+    data: &Arc<Server>,
+    output: &Path,
+    since: Option<&str>,
+    targets: Vec<Target>,
+    cached: bool,
+) -> Result<Manifest> {
+    if data.config.read().await.git.upstream_sparse_url.is_some() {
+        bail!("export needs the git mirror of upstream index, unset upstream_sparse_url first");
+    }
+
+    // read the rows first, so the index bundled later has all of them
+    let (rows, exported) = {
+        let db = data.database.lock().await;
+        let rows = crates::table
+            .filter(crates::owners.is_not_null())
+            .load::<CrateRow>(&db.connection)?;
+        let exported = match since {
+            Some(since) => exported_keys(&db, since)?,
+            None => HashSet::new(),
+        };
+        (rows, exported)
+    };
+    let private = rows.iter().map(|r| r.name.clone()).collect::<Vec<_>>();
+
+    let scratch = ScratchDir::new()?;
+    let head = data.git.index_head().await?;
+    let (commit, has_index) = if since == Some(head.as_str()) {
+        info!("no new index commit since {}", head);
+        (head, false)
+    } else {
+        let commit = data
+            .git
+            .create_bundle(&scratch.0.join(INDEX_BUNDLE), since)
+            .await?;
+        (commit, true)
+    };
+
+    let mut keys = Vec::new();
+    for object in data.storage.list().await? {
+        let name = object.key.split('/').next().unwrap_or_default();
+        if !object.key.ends_with(".crate") || !(cached || private.iter().any(|p| p == name)) {
+            continue;
+        }
+
+        if !exported.contains(&object.key) {
+            keys.push(object.key);
+        }
+    }
+
+    let crates_dir = scratch.0.join(CRATES_DIR);
+    for key in keys {
+        match data.storage.get(&key).await? {
+            Some(bytes) => write_file(&crates_dir.join(&key), &bytes)?,
+            None => warn!("{} is gone while exporting", key),
+        }
+    }
+    for target in targets {
+        for (name, version) in prefetch::resolve(&data.index, target).await? {
+            let key = crate_key(&name, &version);
+            let (bytes, _) = prefetch::fetch(data, &name, &version)
+                .await
+                .context(format!("get {} failed", key))?;
+            write_file(&crates_dir.join(&key), &bytes)?;
+        }
+    }
+
+    let manifest = Manifest {
+        created_at: Local::now().to_string(),
+        commit,
+        base: since.map(|s| s.to_string()),
+        has_index,
+        crates: crate_files(&crates_dir)?,
+        private_crates: rows.len(),
+        conflicts: Vec::new(),
+        dropped: Vec::new(),
+    };
+    fs::write(
+        scratch.0.join(PRIVATE_CRATES),
+        serde_json::to_vec_pretty(&rows)?,
+    )?;
+    fs::write(
+        scratch.0.join(MANIFEST),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    let (dir, file) = (scratch.0.clone(), output.to_path_buf());
+    task::spawn_blocking(move || -> Result<()> {
+        let mut tar =
+            tar::Builder::new(GzEncoder::new(File::create(file)?, Compression::default()));
+        tar.append_dir_all(".", &dir)?;
+        tar.into_inner()?.finish()?;
+        Ok(())
+    })
+    .await??;
+
+    diesel::insert_into(bundle_exports::table)
+        .values(NewExport {
+            commit: &manifest.commit,
+            crates: serde_json::to_string(&manifest.crates)?,
+            created_at: manifest.created_at.clone(),
+        })
+        .execute(&data.database.lock().await.connection)?;
+
+    info!(
+        "exported index {} and {} crate files to {:?}",
+        manifest.commit,
+        manifest.crates.len(),
+        output
+    );
+    Ok(manifest)
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    Ok(fs::write(path, bytes)?)
+}
+
+/// the keys of the crate files under `dir`
+fn crate_files(dir: &Path) -> Result<Vec<String>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut keys = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        for file in fs::read_dir(entry.path())? {
+            keys.push(format!(
+                "{}/{}",
+                entry.file_name().to_string_lossy(),
+                file?.file_name().to_string_lossy()
+            ));
+        }
+    }
+
+    keys.sort();
+    Ok(keys)
+}
+
+/// apply a bundle made by `export`: the crate files are verified against the bundled index, then
+/// the index is merged as the upstream, the crate files are stored and the private crates are
+/// updated. the private crates owned by others here are left out of all of them.
+/// the bundles must be imported in the order they are exported, with the server stopped.
+pub(crate) async fn import(data: &Arc<Server>, bundle: &Path) -> Result<Manifest> {
+    let scratch = ScratchDir::new()?;
+    let (dir, file) = (scratch.0.clone(), bundle.to_path_buf());
+    task::spawn_blocking(move || -> Result<()> {
+        tar::Archive::new(GzDecoder::new(File::open(file)?)).unpack(dir)?;
+        Ok(())
+    })
+    .await?
+    .context("unpack bundle failed")?;
+
+    let mut manifest: Manifest = serde_json::from_slice(&fs::read(scratch.0.join(MANIFEST))?)
+        .context("invalid bundle manifest")?;
+    let rows: Vec<CrateRow> = serde_json::from_slice(&fs::read(scratch.0.join(PRIVATE_CRATES))?)?;
+    // nothing of the conflicting crates is imported, neither the index nor the crate files
+    manifest.conflicts = conflicts(&data.database.lock().await.connection, &rows)?;
+    for name in &manifest.conflicts {
+        warn!(
+            "{} is owned by others here, the bundled one is not imported",
+            name
+        );
+    }
+
+    if manifest.has_index {
+        data.git
+            .fetch_bundle(&scratch.0.join(INDEX_BUNDLE))
+            .await
+            .context(format!(
+                "fetch index {} failed, the bundle is based on {:?}",
+                manifest.commit, manifest.base
+            ))?;
+    }
+
+    // verify them all against the bundled index before importing any of them
+    let mut files = Vec::new();
+    for key in &manifest.crates {
+        let (name, file_name) = split_key(key)?;
+        if is_conflict(&manifest.conflicts, name) {
+            continue;
+        }
+
+        let version = crate_file_version(name, file_name).ok_or(anyhow!("invalid {}", key))?;
+        let bundled = if manifest.has_index {
+            data.git.bundled_index_file(name).await
+        } else {
+            None
+        };
+        let meta = match bundled {
+            Some(content) => find_version(&content, version)?,
+            None => data.index.find(name, version).await?,
+        }
+        .ok_or(anyhow!("{} is not in the index", key))?;
+        let bytes = fs::read(scratch.0.join(CRATES_DIR).join(key))?;
+        if format!("{:x}", Sha256::digest(&bytes)) != meta.cksum {
+            bail!("checksum of {} does not match the index", key);
+        }
+
+        files.push((key, format!("{}-{}", name, version), bytes));
+    }
+
+    if manifest.has_index {
+        manifest.dropped = data
+            .git
+            .import_bundle(manifest.conflicts.clone())
+            .await
+            .context(format!("import index {} failed", manifest.commit))?;
+    }
+    // the crate files of the dropped versions are not the ones in the index here
+    for (key, _, bytes) in files
+        .into_iter()
+        .filter(|(_, v, _)| !manifest.dropped.contains(v))
+    {
+        data.storage.put(key, bytes).await?;
+    }
+    merge_rows(
+        &data.database.lock().await.connection,
+        rows,
+        &manifest.conflicts,
+    )?;
+
+    info!(
+        "imported index {} and {} crate files",
+        manifest.commit,
+        manifest.crates.len()
+    );
+    Ok(manifest)
+}
+
+/// the names of the bundled private crates owned by others here
+fn conflicts(conn: &SqliteConnection, rows: &[CrateRow]) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for row in rows {
+        let owners = crates::table
+            .find(&row.id)
+            .select(crates::owners)
+            .first::<Option<String>>(conn)
+            .optional()?;
+        if matches!(owners, Some(owners) if owners != row.owners) {
+            names.push(row.name.clone());
+        }
+    }
+
+    Ok(names)
+}
+
+fn is_conflict(conflicts: &[String], name: &str) -> bool {
+    conflicts.iter().any(|c| c.eq_ignore_ascii_case(name))
+}
+
+/// a version in the content of an index file
+fn find_version(content: &str, version: &str) -> Result<Option<IndexMetadata>> {
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let meta: IndexMetadata = serde_json::from_str(line.trim())?;
+        if meta.vers == version {
+            return Ok(Some(meta));
+        }
+    }
+
+    Ok(None)
+}
+
+/// put the bundled private crates into the `crates` table except the `conflicts`. a crate not
+/// here is added, one here is updated keeping the larger download counts
+fn merge_rows(conn: &SqliteConnection, rows: Vec<CrateRow>, conflicts: &[String]) -> Result<()> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        for mut row in rows {
+            if is_conflict(conflicts, &row.name) {
+                continue;
+            }
+
+            let local = crates::table
+                .find(&row.id)
+                .first::<CrateRow>(conn)
+                .optional()?;
+            if let Some(local) = local {
+                row.downloads = row.downloads.max(local.downloads);
+                row.recent_downloads = row.recent_downloads.max(local.recent_downloads);
+            }
+
+            diesel::replace_into(crates::table)
+                .values(&row)
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+fn split_key(key: &str) -> Result<(&str, &str)> {
+    let mut parts = key.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(file_name)) => Ok((name, file_name)),
+        _ => Err(anyhow!("invalid {}", key)),
+    }
+}
@@ -0,0 +1,263 @@
+use super::db;
+use crate::{
+    auth::check,
+    config::Config,
+    database::{schema::crate_cache, Database},
+    storage::Storage,
+    Server,
+};
+use anyhow::Result;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use log::{error, info, warn};
+use serde::Serialize;
+use spa_server::re_export::{
+    error::ErrorInternalServerError, get, post, web, HttpResponse, Identity, Responder,
+};
+use std::{collections::HashSet, sync::Arc};
+use tokio::{
+    sync::{Mutex, RwLock},
+    time,
+};
+
+/// how often the eviction runs in background
+const EVICT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// an upstream crate file cached in storage, the private ones are never recorded
+#[derive(Queryable, Insertable)]
+#[table_name = "crate_cache"]
+struct CacheRecord {
+    key: String,
+    size: i64,
+    last_access: NaiveDateTime,
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct CacheUsage {
+    total_bytes: u64,
+    total_files: usize,
+    private_bytes: u64,
+    private_files: usize,
+    cached_bytes: u64,
+    cached_files: usize,
+    quota_bytes: Option<u64>,
+    max_age_days: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EvictResult {
+    evicted_files: usize,
+    evicted_bytes: u64,
+    usage: CacheUsage,
+}
+
+/// record an upstream crate file just cached
+pub(super) fn record(db: &Database, key: &str, size: usize) -> Result<()> {
+    diesel::replace_into(crate_cache::table)
+        .values(CacheRecord {
+            key: key.to_string(),
+            size: size as i64,
+            last_access: Utc::now().naive_utc(),
+        })
+        .execute(&db.connection)?;
+    Ok(())
+}
+
+/// update the last access of a cached crate file, nothing happens for the private ones
+pub(super) fn touch(db: &Database, key: &str) -> Result<()> {
+    diesel::update(crate_cache::table.find(key))
+        .set(crate_cache::last_access.eq(Utc::now().naive_utc()))
+        .execute(&db.connection)?;
+    Ok(())
+}
+
+/// bring the records in line with the storage and count the usage. the upstream crate files
+/// not recorded, eg. cached by an older version, are recorded with their modified time.
+async fn reconcile(database: &Mutex<Database>, storage: &dyn Storage) -> Result<CacheUsage> {
+    let objects = storage.list().await?;
+    let db = database.lock().await;
+    let private = db::private_names(&db)?.into_iter().collect::<HashSet<_>>();
+    let recorded = crate_cache::table
+        .select(crate_cache::key)
+        .load::<String>(&db.connection)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut usage = CacheUsage::default();
+    let mut cached = HashSet::new();
+    let mut untracked = Vec::new();
+    for object in objects {
+        usage.total_bytes += object.size;
+        usage.total_files += 1;
+
+        let name = object.key.split('/').next().unwrap_or_default();
+        if private.contains(name) {
+            usage.private_bytes += object.size;
+            usage.private_files += 1;
+            continue;
+        }
+
+        if !object.key.ends_with(".crate") {
+            continue;
+        }
+
+        usage.cached_bytes += object.size;
+        usage.cached_files += 1;
+        if !recorded.contains(&object.key) {
+            untracked.push(CacheRecord {
+                key: object.key.clone(),
+                size: object.size as i64,
+                last_access: object.modified.naive_utc(),
+            });
+        }
+        cached.insert(object.key);
+    }
+
+    if !untracked.is_empty() {
+        info!("record {} cached crate files", untracked.len());
+        diesel::insert_or_ignore_into(crate_cache::table)
+            .values(&untracked)
+            .execute(&db.connection)?;
+    }
+
+    let gone = recorded.difference(&cached).collect::<Vec<_>>();
+    if !gone.is_empty() {
+        diesel::delete(crate_cache::table.filter(crate_cache::key.eq_any(gone)))
+            .execute(&db.connection)?;
+    }
+
+    Ok(usage)
+}
+
+async fn usage(
+    config: &RwLock<Config>,
+    database: &Mutex<Database>,
+    storage: &dyn Storage,
+) -> Result<CacheUsage> {
+    let mut usage = reconcile(database, storage).await?;
+    let cfg = config.read().await;
+    usage.quota_bytes = cfg.crates.cache_quota_mb.map(|q| q * 1024 * 1024);
+    usage.max_age_days = cfg.crates.cache_max_age_days;
+    Ok(usage)
+}
+
+/// evict the cached upstream crate files not accessed for `cache_max_age_days`, then the
+/// least recently used ones until the cache fits in `cache_quota_mb`.
+/// the private crates are never evicted, they are the only copy we have.
+pub(crate) async fn evict(
+    config: &RwLock<Config>,
+    database: &Mutex<Database>,
+    storage: &dyn Storage,
+) -> Result<EvictResult> {
+    let mut usage = usage(config, database, storage).await?;
+    if config.read().await.registry.offline {
+        // the imported crates can not be downloaded again
+        info!("offline mode, nothing is evicted");
+        return Ok(EvictResult {
+            evicted_files: 0,
+            evicted_bytes: 0,
+            usage,
+        });
+    }
+
+    let cutoff = usage
+        .max_age_days
+        .map(|d| Utc::now().naive_utc() - Duration::days(d as i64));
+    let records = crate_cache::table
+        .order(crate_cache::last_access.asc())
+        .load::<CacheRecord>(&database.lock().await.connection)?;
+
+    let mut evicted_files = 0;
+    let mut evicted_bytes = 0;
+    for record in records {
+        let expired = cutoff.map_or(false, |c| record.last_access < c);
+        let over = usage.quota_bytes.map_or(false, |q| usage.cached_bytes > q);
+        if !expired && !over {
+            // the rest are accessed even later
+            break;
+        }
+
+        if let Err(e) = storage.delete(&record.key).await {
+            warn!("evict {} failed: {:?}", record.key, e);
+            continue;
+        }
+        diesel::delete(crate_cache::table.find(&record.key))
+            .execute(&database.lock().await.connection)?;
+
+        let size = record.size as u64;
+        usage.cached_bytes = usage.cached_bytes.saturating_sub(size);
+        usage.cached_files = usage.cached_files.saturating_sub(1);
+        usage.total_bytes = usage.total_bytes.saturating_sub(size);
+        usage.total_files = usage.total_files.saturating_sub(1);
+        evicted_files += 1;
+        evicted_bytes += size;
+    }
+
+    if evicted_files > 0 {
+        info!(
+            "evicted {} cached crate files, {} bytes",
+            evicted_files, evicted_bytes
+        );
+    }
+    Ok(EvictResult {
+        evicted_files,
+        evicted_bytes,
+        usage,
+    })
+}
+
+/// run the eviction every hour in background
+pub(crate) fn schedule_eviction(
+    config: Arc<RwLock<Config>>,
+    database: Arc<Mutex<Database>>,
+    storage: Arc<dyn Storage>,
+) {
+    let _ = tokio::spawn(async move {
+        loop {
+            time::sleep(EVICT_INTERVAL).await;
+            let enabled = {
+                let cfg = config.read().await;
+                cfg.crates.cache_quota_mb.is_some() || cfg.crates.cache_max_age_days.is_some()
+            };
+            if !enabled {
+                continue;
+            }
+
+            if let Err(e) = evict(&config, &database, &*storage).await {
+                error!("evict cached crates failed: {:?}", e);
+            }
+        }
+    });
+}
+
+/// the disk usage of the crate storage
+#[get("cache")]
+pub(crate) async fn cache_usage(
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    if !check(&id, &*data.database.lock().await)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let usage = usage(&data.config, &data.database, &*data.storage)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("count cache usage failed: {:?}", e)))?;
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+/// run the eviction now
+#[post("cache/evict")]
+pub(crate) async fn evict_cache(
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    if !check(&id, &*data.database.lock().await)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let result = evict(&data.config, &data.database, &*data.storage)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("evict cached crates failed: {:?}", e)))?;
+    Ok(HttpResponse::Ok().json(result))
+}
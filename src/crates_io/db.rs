@@ -1,9 +1,15 @@
-use super::{models::CrateInfo, Crates, Meta, Owner, Owners, SearchResult};
+use super::{
+    models::{CrateInfo, IndexMetadata},
+    Crates, Meta, Owner, Owners, SearchResult,
+};
 pub use crate::database::{
     schema::{accounts::dsl::*, crates::dsl::*},
     Paginate,
 };
-use crate::{auth::AccountWithId, database::Database};
+use crate::{
+    auth::AccountWithId,
+    database::{schema::crate_versions, Database},
+};
 use anyhow::{bail, Context, Result};
 use chrono::Local;
 use diesel::{associations::HasTable, dsl::exists, prelude::*};
@@ -11,6 +17,23 @@ use log::info;
 use semver::Version;
 use std::future::Future;
 
+#[cfg(test)]
+mod test {
+    use super::max_versions;
+
+    #[test]
+    fn test_max_versions() {
+        assert_eq!(
+            max_versions(["2.0.0", "1.4.3", "2.1.0-beta.1"].iter().copied()),
+            (Some("2.1.0-beta.1".to_string()), Some("2.0.0".to_string()))
+        );
+        assert_eq!(
+            max_versions(["0.1.0-alpha"].iter().copied()),
+            (Some("0.1.0-alpha".to_string()), None)
+        );
+    }
+}
+
 pub async fn search<Fut>(
     db: &Database,
     key_word: impl AsRef<str>,
@@ -58,7 +81,43 @@ where
     Ok(result)
 }
 
-pub(super) async fn update(db: &Database, meta: CrateInfo, user: impl Into<String>) -> Result<()> {
+/// the highest version and the highest stable version of `all`, the ones not parsed are
+/// skipped. the caller leaves out the yanked ones
+fn max_versions<'a>(all: impl Iterator<Item = &'a str>) -> (Option<String>, Option<String>) {
+    let mut max: Option<Version> = None;
+    let mut max_stable: Option<Version> = None;
+    for v in all.filter_map(|v| Version::parse(v).ok()) {
+        if max.as_ref().map_or(true, |m| v > *m) {
+            max = Some(v.clone());
+        }
+        if !v.is_prerelease() && max_stable.as_ref().map_or(true, |m| v > *m) {
+            max_stable = Some(v);
+        }
+    }
+
+    (
+        max.map(|v| v.to_string()),
+        max_stable.map(|v| v.to_string()),
+    )
+}
+
+/// insert or update the crate row for a new version, `published` are the versions in
+/// index before it
+pub(super) async fn update(
+    db: &Database,
+    meta: CrateInfo,
+    user: impl Into<String>,
+    published: &[IndexMetadata],
+) -> Result<()> {
+    let (max, max_stable) = max_versions(
+        published
+            .iter()
+            .filter(|m| !m.yanked)
+            .map(|m| m.vers.as_str())
+            .chain(std::iter::once(meta.vers.as_str())),
+    );
+    let max = max.unwrap_or_else(|| meta.vers.clone());
+
     let new_crate;
     let record = crates
         .filter(name.eq(&meta.name))
@@ -71,17 +130,10 @@ pub(super) async fn update(db: &Database, meta: CrateInfo, user: impl Into<Strin
             updated_at: Local::now().to_string(),
             keywords: Some(meta.keywords.join(",")),
             categories: Some(meta.categories.join(",")),
-            max_version: meta.vers.clone(), // new version always the max version, we check it before
-            newest_version: meta.vers.clone(),
-            max_stable_version: {
-                let ver = Version::parse(&meta.vers)?;
-                if ver.pre.len() == 0 && ver.build.len() == 0 {
-                    // it's stable
-                    Some(meta.vers)
-                } else {
-                    record.max_stable_version
-                }
-            },
+            max_version: max,
+            // the version published last, not always the highest
+            newest_version: meta.vers,
+            max_stable_version: max_stable,
             description: meta.description,
             homepage: meta.homepage,
             documentation: meta.documentation,
@@ -99,16 +151,9 @@ pub(super) async fn update(db: &Database, meta: CrateInfo, user: impl Into<Strin
             created_at: Local::now().to_string(),
             downloads: 0,
             recent_downloads: 0,
-            max_version: meta.vers.clone(),
-            newest_version: meta.vers.clone(),
-            max_stable_version: {
-                let ver = Version::parse(&meta.vers)?;
-                if ver.pre.len() == 0 && ver.build.len() == 0 {
-                    Some(meta.vers)
-                } else {
-                    None
-                }
-            },
+            max_version: max,
+            newest_version: meta.vers,
+            max_stable_version: max_stable,
             description: meta.description,
             homepage: meta.homepage,
             documentation: meta.documentation,
@@ -129,6 +174,32 @@ pub(super) fn get_crate(db: &Database, crate_name: impl AsRef<str>) -> Result<Cr
         .first(&db.connection)?)
 }
 
+/// the crates published to this registry, the cached upstream search results have no owners
+pub(super) fn private_names(db: &Database) -> Result<Vec<String>> {
+    Ok(crates::table()
+        .filter(owners.is_not_null())
+        .select(name)
+        .load::<String>(&db.connection)?)
+}
+
+/// name and version of every version of the private crates, the ones recorded in
+/// `crate_versions` and the max version of each crate
+pub(super) fn private_versions(db: &Database) -> Result<Vec<(String, String)>> {
+    let private = crates::table()
+        .filter(owners.is_not_null())
+        .select((name, max_version))
+        .load::<(String, String)>(&db.connection)?;
+    let mut recorded = crate_versions::table
+        .filter(crate_versions::name.eq_any(private.iter().map(|(n, _)| n)))
+        .select((crate_versions::name, crate_versions::version))
+        .load::<(String, String)>(&db.connection)?;
+
+    recorded.extend(private);
+    recorded.sort();
+    recorded.dedup();
+    Ok(recorded)
+}
+
 pub(super) fn get_owners(db: &Database, owner_list: &Vec<String>) -> Result<Owners> {
     let records = accounts::table()
         .filter(username.eq_any(owner_list))
@@ -0,0 +1,115 @@
+use super::{models::Dependency, Index};
+use crate::config::Config;
+use anyhow::Result;
+use semver::{Version, VersionReq};
+use tokio::sync::RwLock;
+
+/// crates.io index, in the git and the sparse protocol
+const CRATES_IO_INDEXES: &[&str] = &[
+    "https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+#[cfg(test)]
+mod test {
+    use super::{normalize_url, same_registry};
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(
+            normalize_url("sparse+https://index.crates.io/"),
+            "https://index.crates.io"
+        );
+        assert_eq!(
+            normalize_url("https://GitHub.com/rust-lang/crates.io-index.git"),
+            "https://github.com/rust-lang/crates.io-index"
+        );
+    }
+
+    #[test]
+    fn test_same_registry() {
+        let upstreams = vec!["https://github.com/rust-lang/crates.io-index".to_string()];
+        assert!(same_registry(
+            "https://github.com/rust-lang/crates.io-index.git",
+            &upstreams
+        ));
+        assert!(!same_registry(
+            "https://my-intranet:8080/git/index",
+            &upstreams
+        ));
+    }
+}
+
+/// compare the index urls without the protocol prefix, the trailing `/` or `.git` and case
+fn normalize_url(url: &str) -> String {
+    let url = url.trim();
+    let url = url.strip_prefix("sparse+").unwrap_or(url);
+    let url = url.trim_end_matches('/');
+    let url = url.strip_suffix(".git").unwrap_or(url);
+    url.to_lowercase()
+}
+
+fn same_registry(url: &str, upstreams: &[String]) -> bool {
+    let url = normalize_url(url);
+    upstreams.iter().any(|u| normalize_url(u) == url)
+}
+
+/// the index urls served by this registry: the upstream mirrored and crates.io
+fn mirrored_indexes(config: &Config) -> Vec<String> {
+    let mut urls = vec![config.git.upstream_url.clone()];
+    urls.extend(config.git.upstream_sparse_url.clone());
+    urls.extend(CRATES_IO_INDEXES.iter().map(|u| u.to_string()));
+    urls
+}
+
+/// point the dependencies from the mirrored upstream to this index, the ones from the other
+/// registries keep their urls. then check the dependencies in this index exist and have a
+/// version not yanked matching the requirement, return the problems found
+pub(super) async fn resolve(
+    config: &RwLock<Config>,
+    index: &Index,
+    deps: &mut [Dependency],
+) -> Result<Vec<String>> {
+    let mirrored = mirrored_indexes(&*config.read().await);
+    let mut errors = Vec::new();
+    for dep in deps.iter_mut() {
+        match &dep.registry {
+            Some(url) if same_registry(url, &mirrored) => dep.registry = None,
+            // we can not look into the other registries
+            Some(_) => continue,
+            None => {}
+        }
+
+        let req = match VersionReq::parse(&dep.version_req) {
+            Ok(req) => req,
+            Err(e) => {
+                errors.push(format!(
+                    "invalid version requirement {} of dependency {}: {}",
+                    dep.version_req, dep.name, e
+                ));
+                continue;
+            }
+        };
+
+        let versions = match index.try_versions(&dep.name.to_lowercase()).await? {
+            Some(versions) => versions,
+            None => {
+                errors.push(format!("dependency {} is not found", dep.name));
+                continue;
+            }
+        };
+        let matched = versions
+            .iter()
+            .filter(|m| !m.yanked)
+            .filter_map(|m| Version::parse(&m.vers).ok())
+            .any(|v| req.matches(&v));
+        if !matched {
+            errors.push(format!(
+                "no version of dependency {} matches {}",
+                dep.name, dep.version_req
+            ));
+        }
+    }
+
+    Ok(errors)
+}
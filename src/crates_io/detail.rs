@@ -0,0 +1,493 @@
+use super::{
+    models::{CrateInfo, Dependency, IndexMetadata},
+    stats,
+    tarball::CrateFile,
+};
+use crate::{
+    auth::check_registry_token,
+    database::{
+        schema::{crate_files, crate_versions},
+        Database,
+    },
+    Server,
+};
+use anyhow::Result;
+use chrono::Local;
+use diesel::prelude::*;
+use pulldown_cmark::{escape::escape_html, html, Options, Parser};
+use semver::Version;
+use serde::Serialize;
+use spa_server::{
+    error_to_json,
+    re_export::{
+        error::{ErrorInternalServerError, ErrorNotFound},
+        get, web, Error, HttpRequest, HttpResponse, Result as WebResult,
+    },
+};
+use std::{collections::HashMap, path::Path};
+
+#[cfg(test)]
+mod test {
+    use super::render_readme;
+
+    #[test]
+    fn test_render_readme() {
+        assert_eq!(
+            render_readme("# Title\n<script>alert(1)</script>", None),
+            "<h1>Title</h1>\n"
+        );
+        assert_eq!(
+            render_readme("[a](javascript:alert(1)) **b**", Some("README.md")),
+            "<p><a rel=\"noopener noreferrer\">a</a> <strong>b</strong></p>\n"
+        );
+        assert_eq!(
+            render_readme("a <b>", Some("README.txt")),
+            "<pre>a &lt;b&gt;</pre>"
+        );
+    }
+}
+
+/// the metadata of a version published, the lists and maps are in json
+#[derive(Queryable, Insertable)]
+#[table_name = "crate_versions"]
+struct VersionRecord {
+    name: String,
+    version: String,
+    description: Option<String>,
+    license: Option<String>,
+    license_file: Option<String>,
+    authors: String,
+    homepage: Option<String>,
+    documentation: Option<String>,
+    repository: Option<String>,
+    readme: Option<String>,
+    readme_file: Option<String>,
+    keywords: String,
+    categories: String,
+    features: String,
+    deps: String,
+    links: Option<String>,
+    crate_size: i64,
+    published_by: String,
+    created_at: String,
+}
+
+#[derive(Serialize)]
+struct User {
+    login: String,
+}
+
+/// a version in the crates.io format, the versions published before the metadata is recorded
+/// only have what is in the index
+#[derive(Serialize)]
+struct VersionInfo {
+    #[serde(rename = "crate")]
+    krate: String,
+    num: String,
+    dl_path: String,
+    readme_path: String,
+    checksum: String,
+    yanked: bool,
+    downloads: i64,
+    features: HashMap<String, Vec<String>>,
+    links: Option<String>,
+    created_at: Option<String>,
+    published_by: Option<User>,
+    license: Option<String>,
+    crate_size: Option<i64>,
+    authors: Vec<String>,
+    description: Option<String>,
+    homepage: Option<String>,
+    documentation: Option<String>,
+    repository: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CrateDetail {
+    id: String,
+    name: String,
+    description: Option<String>,
+    homepage: Option<String>,
+    documentation: Option<String>,
+    repository: Option<String>,
+    max_version: String,
+    newest_version: String,
+    max_stable_version: Option<String>,
+    downloads: i32,
+    recent_downloads: i32,
+    created_at: String,
+    updated_at: String,
+    keywords: Vec<String>,
+    categories: Vec<String>,
+    versions: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DependencyInfo {
+    crate_id: String,
+    req: String,
+    features: Vec<String>,
+    optional: bool,
+    default_features: bool,
+    target: Option<String>,
+    kind: String,
+    registry: Option<String>,
+    /// the name in `Cargo.toml` if renamed
+    rename: Option<String>,
+}
+
+/// keep the metadata of a version just published
+pub(super) fn record(db: &Database, info: &CrateInfo, crate_size: usize, user: &str) -> Result<()> {
+    diesel::replace_into(crate_versions::table)
+        .values(VersionRecord {
+            name: info.name.clone(),
+            version: info.vers.clone(),
+            description: info.description.clone(),
+            license: info.license.clone(),
+            license_file: info.license_file.clone(),
+            authors: serde_json::to_string(&info.authors)?,
+            homepage: info.homepage.clone(),
+            documentation: info.documentation.clone(),
+            repository: info.repository.clone(),
+            readme: info.readme.clone(),
+            readme_file: info.readme_file.clone(),
+            keywords: serde_json::to_string(&info.keywords)?,
+            categories: serde_json::to_string(&info.categories)?,
+            features: serde_json::to_string(&info.features)?,
+            deps: serde_json::to_string(&info.deps)?,
+            links: info.links.clone(),
+            crate_size: crate_size as i64,
+            published_by: user.to_string(),
+            created_at: Local::now().to_string(),
+        })
+        .execute(&db.connection)?;
+    Ok(())
+}
+
+/// keep the files in the crate file of a version just published
+pub(super) fn record_files(db: &Database, info: &CrateInfo, files: &[CrateFile]) -> Result<()> {
+    let rows = files
+        .iter()
+        .map(|f| {
+            (
+                crate_files::name.eq(&info.name),
+                crate_files::version.eq(&info.vers),
+                crate_files::path.eq(&f.path),
+                crate_files::size.eq(f.size),
+            )
+        })
+        .collect::<Vec<_>>();
+    db.connection.transaction::<_, anyhow::Error, _>(|| {
+        diesel::delete(
+            crate_files::table
+                .filter(crate_files::name.eq(&info.name))
+                .filter(crate_files::version.eq(&info.vers)),
+        )
+        .execute(&db.connection)?;
+        diesel::insert_into(crate_files::table)
+            .values(&rows)
+            .execute(&db.connection)?;
+        Ok(())
+    })
+}
+
+/// markdown in html without scripts, styles or unsafe links, the other formats as plain text
+fn render_readme(readme: &str, readme_file: Option<&str>) -> String {
+    let markdown = readme_file
+        .and_then(|f| Path::new(f).extension())
+        .map_or(true, |e| {
+            let e = e.to_string_lossy().to_lowercase();
+            e == "md" || e == "markdown"
+        });
+    if !markdown {
+        let mut text = String::new();
+        // writing to a string never fails
+        let _ = escape_html(&mut text, readme);
+        return format!("<pre>{}</pre>", text);
+    }
+
+    let mut unsafe_html = String::new();
+    html::push_html(
+        &mut unsafe_html,
+        Parser::new_ext(
+            readme,
+            Options::ENABLE_TABLES
+                | Options::ENABLE_FOOTNOTES
+                | Options::ENABLE_STRIKETHROUGH
+                | Options::ENABLE_TASKLISTS,
+        ),
+    );
+    ammonia::clean(&unsafe_html)
+}
+
+fn not_found(what: String) -> Error {
+    ErrorNotFound(format!("{} is not found", what))
+}
+
+/// the versions published to this registry, the highest first
+async fn load_versions(
+    data: &Server,
+    name: &str,
+) -> WebResult<(Vec<IndexMetadata>, HashMap<String, VersionRecord>)> {
+    let mut published = data
+        .index
+        .published(name)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if published.is_empty() {
+        return Err(not_found(format!("crate {}", name)));
+    }
+    published.sort_by(
+        |a, b| match (Version::parse(&a.vers), Version::parse(&b.vers)) {
+            (Ok(a), Ok(b)) => b.cmp(&a),
+            _ => b.vers.cmp(&a.vers),
+        },
+    );
+
+    let records = crate_versions::table
+        .filter(crate_versions::name.eq(name))
+        .load::<VersionRecord>(&data.database.lock().await.connection)
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|r| (r.version.clone(), r))
+        .collect();
+    Ok((published, records))
+}
+
+fn version_info(
+    meta: IndexMetadata,
+    record: Option<&VersionRecord>,
+    downloads: &HashMap<String, i64>,
+) -> VersionInfo {
+    let parse = |json: &str| serde_json::from_str(json).unwrap_or_default();
+    VersionInfo {
+        dl_path: format!("/api/v1/crates/{}/{}/download", meta.name, meta.vers),
+        readme_path: format!("/api/v1/crates/{}/{}/readme", meta.name, meta.vers),
+        downloads: downloads.get(&meta.vers).copied().unwrap_or_default(),
+        krate: meta.name,
+        num: meta.vers,
+        checksum: meta.cksum,
+        yanked: meta.yanked,
+        features: meta.features,
+        links: meta.links,
+        created_at: record.map(|r| r.created_at.clone()),
+        published_by: record.map(|r| User {
+            login: r.published_by.clone(),
+        }),
+        license: record.and_then(|r| r.license.clone()),
+        crate_size: record.map(|r| r.crate_size),
+        authors: record.map(|r| parse(&r.authors)).unwrap_or_default(),
+        description: record.and_then(|r| r.description.clone()),
+        homepage: record.and_then(|r| r.homepage.clone()),
+        documentation: record.and_then(|r| r.documentation.clone()),
+        repository: record.and_then(|r| r.repository.clone()),
+    }
+}
+
+async fn version_infos(data: &Server, name: &str) -> WebResult<Vec<VersionInfo>> {
+    let (published, records) = load_versions(data, name).await?;
+    let downloads = stats::version_totals(&*data.database.lock().await, name)
+        .map_err(ErrorInternalServerError)?;
+    Ok(published
+        .into_iter()
+        .map(|m| {
+            let record = records.get(&m.vers);
+            version_info(m, record, &downloads)
+        })
+        .collect())
+}
+
+fn split_list(list: Option<String>) -> Vec<String> {
+    list.map(|l| {
+        l.split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// a crate published to this registry and its versions
+#[error_to_json]
+#[get("{crate_name}")]
+pub async fn crate_detail(
+    req: HttpRequest,
+    data: web::Data<Server>,
+    info: web::Path<(String,)>,
+) -> WebResult<HttpResponse> {
+    check_registry_token(&req, &data).await?;
+
+    let (crate_name,) = info.into_inner();
+    let versions = version_infos(&data, &crate_name).await?;
+    let krate = super::db::get_crate(&*data.database.lock().await, &crate_name)
+        .map_err(|_| not_found(format!("crate {}", crate_name)))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "crate": CrateDetail {
+            versions: versions.iter().map(|v| v.num.clone()).collect(),
+            keywords: split_list(krate.keywords),
+            categories: split_list(krate.categories),
+            id: krate.id,
+            name: krate.name,
+            description: krate.description,
+            homepage: krate.homepage,
+            documentation: krate.documentation,
+            repository: krate.repository,
+            max_version: krate.max_version,
+            newest_version: krate.newest_version,
+            max_stable_version: krate.max_stable_version,
+            downloads: krate.downloads,
+            recent_downloads: krate.recent_downloads,
+            created_at: krate.created_at,
+            updated_at: krate.updated_at,
+        },
+        "versions": versions,
+    })))
+}
+
+#[error_to_json]
+#[get("{crate_name}/versions")]
+pub async fn list_versions(
+    req: HttpRequest,
+    data: web::Data<Server>,
+    info: web::Path<(String,)>,
+) -> WebResult<HttpResponse> {
+    check_registry_token(&req, &data).await?;
+
+    let (crate_name,) = info.into_inner();
+    let versions = version_infos(&data, &crate_name).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "versions": versions })))
+}
+
+#[error_to_json]
+#[get("{crate_name}/{version}")]
+pub async fn version_detail(
+    req: HttpRequest,
+    data: web::Data<Server>,
+    info: web::Path<(String, String)>,
+) -> WebResult<HttpResponse> {
+    check_registry_token(&req, &data).await?;
+
+    let (crate_name, version) = info.into_inner();
+    let version = version_infos(&data, &crate_name)
+        .await?
+        .into_iter()
+        .find(|v| v.num == version)
+        .ok_or_else(|| not_found(format!("{}-{}", crate_name, version)))?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "version": version })))
+}
+
+/// the dependencies of a version as published, the ones before the metadata is recorded are
+/// from the index
+#[error_to_json]
+#[get("{crate_name}/{version}/dependencies")]
+pub async fn version_dependencies(
+    req: HttpRequest,
+    data: web::Data<Server>,
+    info: web::Path<(String, String)>,
+) -> WebResult<HttpResponse> {
+    check_registry_token(&req, &data).await?;
+
+    let (crate_name, version) = info.into_inner();
+    let (published, records) = load_versions(&data, &crate_name).await?;
+    let meta = published
+        .into_iter()
+        .find(|m| m.vers == version)
+        .ok_or_else(|| not_found(format!("{}-{}", crate_name, version)))?;
+
+    let deps = match records.get(&version) {
+        Some(r) => serde_json::from_str::<Vec<Dependency>>(&r.deps)
+            .map_err(ErrorInternalServerError)?
+            .into_iter()
+            .map(|d| DependencyInfo {
+                crate_id: d.name,
+                req: d.version_req,
+                features: d.features,
+                optional: d.optional,
+                default_features: d.default_features,
+                target: d.target,
+                kind: d.kind.unwrap_or_else(|| "normal".to_string()),
+                registry: d.registry,
+                rename: d.explicit_name_in_toml,
+            })
+            .collect::<Vec<_>>(),
+        None => meta
+            .deps
+            .into_iter()
+            .map(|d| {
+                let (crate_id, rename) = match d.package {
+                    Some(package) => (package, Some(d.name)),
+                    None => (d.name, None),
+                };
+                DependencyInfo {
+                    crate_id,
+                    rename,
+                    req: d.req,
+                    features: d.features,
+                    optional: d.optional,
+                    default_features: d.default_features,
+                    target: d.target,
+                    kind: d.kind.unwrap_or_else(|| "normal".to_string()),
+                    registry: d.registry,
+                }
+            })
+            .collect(),
+    };
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "dependencies": deps })))
+}
+
+/// the readme in sanitized html
+#[error_to_json]
+#[get("{crate_name}/{version}/readme")]
+pub async fn version_readme(
+    req: HttpRequest,
+    data: web::Data<Server>,
+    info: web::Path<(String, String)>,
+) -> WebResult<HttpResponse> {
+    check_registry_token(&req, &data).await?;
+
+    let (crate_name, version) = info.into_inner();
+    let record = crate_versions::table
+        .find((&crate_name, &version))
+        .first::<VersionRecord>(&data.database.lock().await.connection)
+        .optional()
+        .map_err(ErrorInternalServerError)?;
+    let (readme, readme_file) = match record {
+        Some(VersionRecord {
+            readme: Some(readme),
+            readme_file,
+            ..
+        }) => (readme, readme_file),
+        _ => return Err(not_found(format!("readme of {}-{}", crate_name, version))),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(render_readme(&readme, readme_file.as_deref())))
+}
+
+/// the files in the crate file of a version, the versions published before the files are
+/// recorded have none
+#[error_to_json]
+#[get("{crate_name}/{version}/files")]
+pub async fn version_files(
+    req: HttpRequest,
+    data: web::Data<Server>,
+    info: web::Path<(String, String)>,
+) -> WebResult<HttpResponse> {
+    check_registry_token(&req, &data).await?;
+
+    let (crate_name, version) = info.into_inner();
+    let files = crate_files::table
+        .filter(crate_files::name.eq(&crate_name))
+        .filter(crate_files::version.eq(&version))
+        .order(crate_files::path.asc())
+        .select((crate_files::path, crate_files::size))
+        .load::<CrateFile>(&data.database.lock().await.connection)
+        .map_err(ErrorInternalServerError)?;
+    if files.is_empty() {
+        return Err(not_found(format!("files of {}-{}", crate_name, version)));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "files": files })))
+}
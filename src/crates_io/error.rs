@@ -0,0 +1,62 @@
+use spa_server::re_export::{http::StatusCode, HttpResponse, ResponseError};
+use thiserror::Error;
+
+/// why a crate can not be downloaded from upstream, sent to cargo as
+/// `{"errors":[{"detail":"..."}]}` with the status code
+#[derive(Error, Debug, Clone)]
+pub(crate) enum DownloadError {
+    #[error("{name}-{version} is not found in the index")]
+    NotInIndex { name: String, version: String },
+    #[error("read index of {name} failed: {reason}")]
+    Index { name: String, reason: String },
+    #[error("upstream returned {status} for {url}")]
+    UpstreamStatus { url: String, status: u16 },
+    #[error("request {url} failed: {reason}")]
+    Http { url: String, reason: String },
+    #[error("checksum of {key} does not match the index in {attempts} attempts, expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        key: String,
+        expected: String,
+        actual: String,
+        attempts: u32,
+    },
+    #[error("store {key} failed: {reason}")]
+    Storage { key: String, reason: String },
+    #[error("{key} is not imported into this offline mirror")]
+    Offline { key: String },
+    #[error("download task of {key} failed: {reason}")]
+    Task { key: String, reason: String },
+}
+
+impl DownloadError {
+    /// whether it may succeed if try again, a checksum mismatch may be a corrupted transfer
+    pub(super) fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::UpstreamStatus { status, .. } => *status == 429 || *status >= 500,
+            DownloadError::Http { .. } | DownloadError::ChecksumMismatch { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl ResponseError for DownloadError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DownloadError::NotInIndex { .. } | DownloadError::Offline { .. } => {
+                StatusCode::NOT_FOUND
+            }
+            DownloadError::UpstreamStatus { status: 404, .. } => StatusCode::NOT_FOUND,
+            DownloadError::Index { .. }
+            | DownloadError::UpstreamStatus { .. }
+            | DownloadError::Http { .. }
+            | DownloadError::ChecksumMismatch { .. } => StatusCode::BAD_GATEWAY,
+            DownloadError::Storage { .. } | DownloadError::Task { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(spa_server::quick_err(self.to_string()))
+    }
+}
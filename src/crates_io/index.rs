@@ -1,23 +1,127 @@
-use super::models::{CrateInfo, IndexMetadata};
+use super::{
+    models::{CrateInfo, IndexDependency, IndexMetadata},
+    upstream::UpstreamIndex,
+};
 use crate::config::Config;
 use anyhow::{anyhow, bail, Context, Result};
-use log::debug;
+use log::{debug, warn};
 use semver::Version;
 use std::{path::PathBuf, sync::Arc};
 use tokio::{
-    fs::{self, File, OpenOptions},
-    io::AsyncBufReadExt,
-    io::{AsyncWriteExt, BufReader},
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
     sync::RwLock,
 };
 
+#[cfg(test)]
+mod test {
+    use super::duplicate_of;
+
+    #[test]
+    fn test_duplicate_of() {
+        let published = ["1.0.0", "2.0.0", "1.4.2+build.5"];
+        assert_eq!(
+            duplicate_of(published.iter().copied(), "1.4.3").unwrap(),
+            None
+        );
+        assert_eq!(
+            duplicate_of(published.iter().copied(), "2.0.0-rc.1").unwrap(),
+            None
+        );
+        assert_eq!(
+            duplicate_of(published.iter().copied(), "2.0.0").unwrap(),
+            Some("2.0.0")
+        );
+        assert_eq!(
+            duplicate_of(published.iter().copied(), "1.4.2").unwrap(),
+            Some("1.4.2+build.5")
+        );
+    }
+}
+
+/// the published version same as `new`, the build metadata is not taken into account.
+/// the versions can be published in any order, eg. a backport after the next major
+fn duplicate_of<'a>(
+    published: impl Iterator<Item = &'a str>,
+    new: &str,
+) -> Result<Option<&'a str>> {
+    let new = Version::parse(new)?;
+    for vers in published {
+        let old = Version::parse(vers)?;
+        if (&old.major, &old.minor, &old.patch, &old.pre)
+            == (&new.major, &new.minor, &new.patch, &new.pre)
+        {
+            return Ok(Some(vers));
+        }
+    }
+
+    Ok(None)
+}
+
 pub struct Index {
     config: Arc<RwLock<Config>>,
+    upstream: Arc<UpstreamIndex>,
+}
+
+/// the index file path relative to the index root, eg. `se/rd/serde`, `3/s/syn`
+pub(crate) fn relative_path(name: &str) -> String {
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[0..1], name),
+        _ => format!("{}/{}/{}", &name[0..2], &name[2..4], name),
+    }
 }
 
 impl Index {
     pub fn new(config: Arc<RwLock<Config>>) -> Self {
-        Index { config }
+        Index {
+            upstream: Arc::new(UpstreamIndex::new(config.clone())),
+            config,
+        }
+    }
+
+    /// the upstream index in lazy mirror mode, shared with the others checking upstream
+    pub(crate) fn upstream(&self) -> &Arc<UpstreamIndex> {
+        &self.upstream
+    }
+
+    /// the whole index file of a crate, in lazy mirror mode, it's the upstream index file
+    /// fetched on demand followed by the private crates in work tree. if the upstream one can
+    /// not be fetched, the work tree one is used alone, it's an error only if there is none.
+    pub(crate) async fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let upstream = self
+            .upstream
+            .get(&relative_path(&name.to_lowercase()))
+            .await
+            .context("fetch upstream index failed");
+
+        let index_file = self.get_path(name).await;
+        let local = if index_file.exists() {
+            Some(fs::read(index_file).await?)
+        } else {
+            None
+        };
+
+        let upstream = match upstream {
+            Ok(upstream) => upstream,
+            Err(e) if local.is_some() => {
+                warn!("{:#}, only the local index of {} is used", e, name);
+                None
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(match (upstream, local) {
+            (Some(mut upstream), Some(local)) => {
+                if !upstream.is_empty() && !upstream.ends_with(b"\n") {
+                    upstream.push(b'\n');
+                }
+                upstream.extend(local);
+                Some(upstream)
+            }
+            (upstream, local) => upstream.or(local),
+        })
     }
 
     pub async fn get_exact(
@@ -27,35 +131,88 @@ impl Index {
     ) -> Result<IndexMetadata> {
         let version = version.as_ref();
         let name = name.as_ref();
-        let index_file = self.get_path(name).await;
-        if !index_file.exists() {
-            bail!("can not found index file in work tree: {:?}", index_file);
-        }
+        self.find(name, version).await?.ok_or(anyhow!(
+            "metadata not found, name: {} version: {}",
+            name,
+            version
+        ))
+    }
+
+    /// the metadata of a version, `None` if the crate or the version is not in index
+    pub(crate) async fn find(&self, name: &str, version: &str) -> Result<Option<IndexMetadata>> {
+        let content = match self.read(name).await? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        for line in String::from_utf8_lossy(&content).lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
 
-        let mut lines = BufReader::new(File::open(index_file).await?).lines();
-        while let Some(line) = lines.next_line().await? {
             let meta: IndexMetadata =
                 serde_json::from_str(line.trim()).context("get_exact decode metadata failed")?;
             if meta.vers == version {
-                return Ok(meta);
+                return Ok(Some(meta));
             }
         }
 
-        Err(anyhow!(
-            "metadata not found, name: {} version: {}",
-            name,
-            version
-        ))
+        Ok(None)
     }
 
-    async fn get_path(&self, name: &str) -> PathBuf {
-        let working_path = &self.config.read().await.git.working_path;
-        let index_file = match name.len() {
-            1 => working_path.join("1").join(name),
-            2 => working_path.join("2").join(name),
-            3 => working_path.join("3").join(&name[0..1]).join(name),
-            _ => working_path.join(&name[0..2]).join(&name[2..4]).join(name),
-        };
+    /// all the versions of a crate in index, the unparsable lines are skipped
+    pub(crate) async fn versions(&self, name: &str) -> Result<Vec<IndexMetadata>> {
+        self.try_versions(name)
+            .await?
+            .ok_or(anyhow!("can not found index file of {}", name))
+    }
+
+    /// the same as `versions`, `None` if the crate is not in index
+    pub(crate) async fn try_versions(&self, name: &str) -> Result<Option<Vec<IndexMetadata>>> {
+        Ok(self.read(name).await?.map(|content| {
+            String::from_utf8_lossy(&content)
+                .lines()
+                .filter_map(|l| serde_json::from_str(l.trim()).ok())
+                .collect()
+        }))
+    }
+
+    /// the versions published to this registry, in work tree only
+    pub(crate) async fn published(&self, name: &str) -> Result<Vec<IndexMetadata>> {
+        let index_file = self.get_path(name).await;
+        if !index_file.exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = fs::read(index_file).await?;
+        let mut versions = Vec::new();
+        for line in String::from_utf8_lossy(&content).lines() {
+            if !line.trim().is_empty() {
+                versions.push(serde_json::from_str(line.trim())?);
+            }
+        }
+
+        Ok(versions)
+    }
+
+    /// fail if `version` of `name` is published already
+    pub(crate) async fn check_new_version(&self, name: &str, version: &str) -> Result<()> {
+        let published = self.published(name).await?;
+        if let Some(old) = duplicate_of(published.iter().map(|m| m.vers.as_str()), version)? {
+            bail!("{}-{} is already published as {}", name, version, old);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn get_path(&self, name: &str) -> PathBuf {
+        let index_file = self
+            .config
+            .read()
+            .await
+            .git
+            .working_path
+            .join(relative_path(name));
 
         debug!("index file path: {:?}", index_file);
         index_file
@@ -98,29 +255,10 @@ impl Index {
 
     pub async fn append(&self, new: CrateInfo, cksum: impl Into<String>) -> Result<()> {
         let index_path = self.get_path(&new.name).await;
+        self.check_new_version(&new.name, &new.vers).await?;
         let mut index_file;
         if index_path.exists() {
-            index_file = OpenOptions::new()
-                .read(true)
-                .append(true)
-                .open(index_path)
-                .await?;
-            let reader = BufReader::new(index_file);
-            let mut lines = reader.lines();
-            while let Some(line) = lines.next_line().await? {
-                let meta: IndexMetadata = serde_json::from_str(line.trim())?;
-                let old_version = Version::parse(&meta.vers)?;
-                let new_version = Version::parse(&new.vers)?;
-                if old_version >= new_version {
-                    bail!(
-                        "the new version {} is less than or equal to old version {}",
-                        new.vers,
-                        meta.vers,
-                    );
-                }
-            }
-
-            index_file = lines.into_inner().into_inner(); // get the index_file back
+            index_file = OpenOptions::new().append(true).open(index_path).await?;
         } else {
             fs::create_dir_all(
                 index_path
@@ -138,7 +276,7 @@ impl Index {
         let meta = IndexMetadata {
             name: new.name.clone(),
             vers: new.vers,
-            deps: new.deps,
+            deps: new.deps.into_iter().map(IndexDependency::from).collect(),
             cksum: cksum.into(),
             features: new.features,
             yanked: false,
@@ -1,65 +1,138 @@
+mod bundle;
+mod cache;
 mod db;
+mod deps;
+mod detail;
+mod error;
 mod index;
 mod models;
+mod prefetch;
+mod shadow;
+mod sources;
+mod stats;
+mod tarball;
+mod upstream;
+mod validate;
+mod verify;
+mod warnings;
 
 use self::models::Crates;
 use crate::{
-    auth::{get_user_by_token, Account},
+    auth::{check_registry_token, get_user_by_token, Account},
     database::Database,
+    storage::{crate_key, ByteStream},
     Server,
 };
-use anyhow::{anyhow, Context};
-use futures::StreamExt;
+use anyhow::{anyhow, bail, Context};
+pub(crate) use bundle::{export, import};
+pub(crate) use cache::{cache_usage, evict_cache, schedule_eviction};
+pub(crate) use detail::{
+    crate_detail, list_versions, version_dependencies, version_detail, version_files,
+    version_readme,
+};
+use diesel::Connection;
+use error::DownloadError;
+use futures::{
+    channel::mpsc::{self, Sender},
+    future::{BoxFuture, Shared},
+    stream::{self, BoxStream},
+    Future, FutureExt, SinkExt, StreamExt,
+};
+pub(crate) use index::relative_path;
 pub use index::Index;
-use log::{debug, info, warn};
-use models::CrateInfo;
-use reqwest::{Client, Url};
+use log::{debug, error, info, warn};
+use models::{CrateInfo, IndexMetadata};
+pub(crate) use prefetch::{
+    begin_prefetch, prefetch, prefetch_status, start_prefetch, PrefetchProgress, PrefetchRequest,
+};
+use reqwest::{Client, Response, Url};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::Digest;
+pub(crate) use shadow::{allow_shadow, disallow_shadow, list_shadow, shadowed_names};
+use sources::Candidate;
+pub(crate) use sources::{list_sources, Sources};
 use spa_server::{
     error_to_json,
     re_export::{
         delete,
-        error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
-        get, put, web, HttpRequest, HttpResponse, NamedFile, Responder, Result,
+        error::{
+            ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorPayloadTooLarge,
+            ErrorUnauthorized,
+        },
+        get,
+        http::header,
+        put, web, HttpRequest, HttpResponse, Responder, Result,
     },
 };
+pub(crate) use stats::{crate_downloads, schedule_refresh};
 use std::{
-    fs,
+    collections::HashMap,
+    env,
     io::{BufReader, Read},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
+use tokio::{fs, io::AsyncWriteExt, time};
+pub(crate) use upstream::UpstreamIndex;
+use validate::Limits;
+pub(crate) use verify::{verify, verify_storage};
+pub(crate) use warnings::{allow_metadata, disallow_metadata, list_allowed};
 
 #[get("/{name}/{version}/download")]
 pub async fn download(
+    req: HttpRequest,
     info: web::Path<(String, String)>,
     data: web::Data<Server>,
 ) -> Result<impl Responder> {
+    check_registry_token(&req, &data).await?;
+
     let (name, version) = info.into_inner();
-    let crate_name = format!("{}-{}.crate", name, version);
+    let key = crate_key(&name, &version);
     let data = data.into_inner();
-    let config = data.config.read().await;
-    let crate_path = config.crates.storage_path.join(&name).join(&crate_name);
-    let crate_file = match NamedFile::open(&crate_path) {
-        Ok(f) => f,
-        Err(_) => {
-            warn!("{} is not in our storage, get it from upstream", crate_name);
-            download_from_upstream(&crate_path, name, version, &data)
+    let stored = data
+        .storage
+        .get_stream(&key)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("read {} failed: {:?}", key, e)))?;
+    let body = match stored {
+        Some(chunks) => {
+            if let Err(e) = cache::touch(&*data.database.lock().await, &key) {
+                warn!("update last access of {} failed: {:?}", key, e);
+            }
+            body_of(&key, chunks)
+        }
+        None => {
+            warn!("{} is not in our storage, get it from upstream", key);
+            stream_from_upstream(&key, &name, &version, &data)
                 .await
                 .map_err(|e| {
-                    ErrorInternalServerError(format!(
-                        "download crate from upstream failed: {:?}",
-                        e
-                    ))
-                })?;
-
-            NamedFile::open(crate_path)?
+                    warn!("download {} from upstream failed: {}", key, e);
+                    e
+                })?
         }
     };
 
-    Ok(crate_file)
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .streaming(count_when_sent(body, name, version, data)))
+}
+
+/// count the download after the whole body is sent, not if it fails or the client is gone
+fn count_when_sent(
+    body: BodyStream,
+    name: String,
+    version: String,
+    data: Arc<Server>,
+) -> BodyStream {
+    let count = stream::once(async move {
+        if let Err(e) = stats::record(&*data.database.lock().await, &name, &version) {
+            warn!("count download of {}-{} failed: {:?}", name, version, e);
+        }
+    })
+    .filter_map(|_| async { None });
+    body.chain(count).boxed()
 }
 
 fn unsecure_http_client() -> anyhow::Result<Client> {
@@ -70,49 +143,461 @@ fn unsecure_http_client() -> anyhow::Result<Client> {
         .build()?)
 }
 
+/// the upstream downloads in flight, keyed by the crate key
+pub(crate) type Downloads = std::sync::Mutex<HashMap<String, Shared<PendingDownload>>>;
+/// done when the crate is in our storage
+type PendingDownload = BoxFuture<'static, Result<(), DownloadError>>;
+
+/// a crate is downloaded at most this many times, the checksum mismatches and
+/// the transient errors are retried with backoff
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+/// the chunks streamed to a client ahead of it, the upstream is read as fast as the client reads
+const STREAM_CHUNKS: usize = 16;
+
+/// download a crate from upstream into our storage, the concurrent downloads of the same crate
+/// are coalesced into one, they all wait for it
 async fn download_from_upstream(
-    path: impl AsRef<Path>,
-    name: impl AsRef<str>,
-    version: impl AsRef<str>,
+    key: &str,
+    name: &str,
+    version: &str,
     data: &Arc<Server>,
-) -> anyhow::Result<()> {
-    let path = path.as_ref();
-    let client = unsecure_http_client()?;
-    let name = name.as_ref();
-    let version = version.as_ref();
-    for i in 0u32..5 {
-        let response = client
-            .get(
-                Url::parse(&format!(
-                    "{}/api/v1/crates/{}/{}/download",
-                    data.config.read().await.crates.upstream_url,
-                    name,
-                    version
-                ))
-                .unwrap(),
-            )
-            .send()
-            .await?;
-
-        let bytes = response.bytes().await?;
-        let checksum = format!("{:x}", sha2::Sha256::digest(&bytes));
-        let meta = data.index.get_exact(name, version).await?;
-        if meta.cksum != checksum {
-            warn!("checksum not match, try download again, retry time: {}", i);
-            continue;
+) -> Result<(), DownloadError> {
+    let (name, version) = (name.to_string(), version.to_string());
+    let (pending, _) = coalesce(key, data, move |key, data| async move {
+        fetch_from_upstream(&key, &name, &version, &data).await
+    });
+
+    pending.await
+}
+
+/// the pending download of `key`, `start` it if there is none, and whether it is started here
+fn coalesce<F, Fut>(key: &str, data: &Arc<Server>, start: F) -> (Shared<PendingDownload>, bool)
+where
+    F: FnOnce(String, Arc<Server>) -> Fut,
+    Fut: Future<Output = Result<(), DownloadError>> + Send + 'static,
+{
+    let mut downloads = data.downloads.lock().unwrap();
+    if let Some(pending) = downloads.get(key) {
+        debug!("{} is downloading, wait for it", key);
+        return (pending.clone(), false);
+    }
+
+    let future = start(key.to_string(), data.clone());
+    let (task_key, data) = (key.to_string(), data.clone());
+    // run in its own task, so the download goes on even if the requests are gone.
+    // the entry is removed after `downloads` is unlocked here
+    let task = tokio::spawn(async move {
+        let result = future.await;
+        data.downloads.lock().unwrap().remove(&task_key);
+        result
+    });
+    let task_key = key.to_string();
+    let pending = async move {
+        match task.await {
+            Ok(result) => result,
+            Err(e) => Err(DownloadError::Task {
+                key: task_key,
+                reason: e.to_string(),
+            }),
         }
+    }
+    .boxed()
+    .shared();
+    downloads.insert(key.to_string(), pending.clone());
+    (pending, true)
+}
+
+/// the crate body sent to the client
+type BodyStream = BoxStream<'static, Result<web::Bytes, DownloadError>>;
+type BodySender = Sender<Result<web::Bytes, DownloadError>>;
+
+/// a crate file read from our storage as the response body
+fn body_of(key: &str, chunks: ByteStream) -> BodyStream {
+    let key = key.to_string();
+    chunks
+        .map(move |chunk| {
+            chunk
+                .map(web::Bytes::from)
+                .map_err(|e| DownloadError::Storage {
+                    key: key.clone(),
+                    reason: format!("{:#}", e),
+                })
+        })
+        .boxed()
+}
+
+/// the crate just stored by a download
+async fn read_stored(key: &str, data: &Server) -> Result<BodyStream, DownloadError> {
+    let storage_error = |reason: String| DownloadError::Storage {
+        key: key.to_string(),
+        reason,
+    };
+    match data.storage.get_stream(key).await {
+        Ok(Some(chunks)) => Ok(body_of(key, chunks)),
+        Ok(None) => Err(storage_error("it is gone just after stored".to_string())),
+        Err(e) => Err(storage_error(format!("{:#}", e))),
+    }
+}
+
+/// download a crate from upstream and send it to the client at the same time, it is hashed and
+/// written to a temp file on the way, and stored only if the checksum matches. the others asking
+/// for the same crate meanwhile wait for it to be stored, then read it from our storage
+async fn stream_from_upstream(
+    key: &str,
+    name: &str,
+    version: &str,
+    data: &Arc<Server>,
+) -> Result<BodyStream, DownloadError> {
+    let (tx, mut rx) = mpsc::channel(STREAM_CHUNKS);
+    let (name, version) = (name.to_string(), version.to_string());
+    let (pending, started) = coalesce(key, data, move |key, data| async move {
+        tee_from_upstream(&key, &name, &version, &data, tx).await
+    });
+    if !started {
+        pending.await?;
+        return read_stored(key, data).await;
+    }
+
+    // the errors before the first chunk are sent with their status code
+    match rx.next().await {
+        Some(Ok(first)) => Ok(stream::once(async { Ok(first) }).chain(rx).boxed()),
+        Some(Err(e)) => Err(e),
+        None => Ok(stream::empty().boxed()),
+    }
+}
+
+/// removed on drop
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// stream the crate to `tx` once, then do it the buffered way with retries if it fails.
+/// `tx` gets the whole result if nothing is sent yet, otherwise the error ends the stream
+async fn tee_from_upstream(
+    key: &str,
+    name: &str,
+    version: &str,
+    data: &Arc<Server>,
+    mut tx: BodySender,
+) -> Result<(), DownloadError> {
+    let mut sent = false;
+    let error = match tee_once(key, name, version, data, &mut tx, &mut sent).await {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+
+    if sent {
+        warn!("stream {} failed: {}", key, error);
+        let _ = tx.send(Err(error)).await;
+        // the client is done, go on for the cache
+        drop(tx);
+        return fetch_from_upstream(key, name, version, data).await;
+    }
 
-        let dir = path.parent().ok_or(anyhow!("no parent for {:?}", path))?;
-        if !dir.exists() {
-            fs::create_dir_all(dir)?;
+    warn!("stream {} failed: {}, try again", key, error);
+    let result = fetch_from_upstream(key, name, version, data).await;
+    let body = match &result {
+        Ok(()) => read_stored(key, data).await,
+        Err(e) => Err(e.clone()),
+    };
+    match body {
+        Ok(mut body) => {
+            while let Some(chunk) = body.next().await {
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
         }
-        fs::write(path, bytes)?;
-        break;
+        Err(e) => {
+            let _ = tx.send(Err(e)).await;
+        }
+    }
+    result
+}
+
+async fn tee_once(
+    key: &str,
+    name: &str,
+    version: &str,
+    data: &Arc<Server>,
+    tx: &mut BodySender,
+    sent: &mut bool,
+) -> Result<(), DownloadError> {
+    let meta = find_meta(name, version, data).await?;
+    // stream from the first source, the others are tried in the buffered way if it fails
+    let source = data
+        .sources
+        .candidates(&data.config, name, version)
+        .await?
+        .remove(0);
+
+    let client = unsecure_http_client().map_err(|e| DownloadError::Http {
+        url: source.url.clone(),
+        reason: format!("{:#}", e),
+    })?;
+    let result = match send(&client, &source).await {
+        Ok(response) => tee_response(key, &meta, response, data, tx, sent).await,
+        Err(e) => Err(e),
+    };
+    match &result {
+        Ok(_) => data.sources.succeeded(&source.source),
+        Err(e) => data.sources.failed(&source.source, e),
+    }
+    result
+}
+
+async fn tee_response(
+    key: &str,
+    meta: &IndexMetadata,
+    mut response: Response,
+    data: &Arc<Server>,
+    tx: &mut BodySender,
+    sent: &mut bool,
+) -> Result<(), DownloadError> {
+    let storage_error = |e: anyhow::Error| DownloadError::Storage {
+        key: key.to_string(),
+        reason: format!("{:#}", e),
+    };
+    let url = response.url().to_string();
+    let http_error = |e: reqwest::Error| DownloadError::Http {
+        url: url.clone(),
+        reason: e.to_string(),
+    };
+
+    let tmp = TempFile(env::temp_dir().join(format!("mirror-{:x}.crate", rand::random::<u64>())));
+    let mut file = fs::File::create(&tmp.0)
+        .await
+        .map_err(|e| storage_error(e.into()))?;
+    let mut hasher = sha2::Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = response.chunk().await.map_err(http_error)? {
+        hasher.update(&chunk);
+        size += chunk.len();
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| storage_error(e.into()))?;
+        // waits for a slow client, the client may be gone, go on for the cache
+        *sent |= tx.send(Ok(chunk)).await.is_ok();
     }
+    file.flush().await.map_err(|e| storage_error(e.into()))?;
 
+    let checksum = format!("{:x}", hasher.finalize());
+    if checksum != meta.cksum {
+        quarantine(
+            data,
+            &meta.name,
+            &meta.vers,
+            &checksum,
+            Quarantined::File(&tmp.0),
+        )
+        .await;
+        return Err(DownloadError::ChecksumMismatch {
+            key: key.to_string(),
+            expected: meta.cksum.clone(),
+            actual: checksum,
+            attempts: 1,
+        });
+    }
+
+    // the checksum matches, put it in place
+    data.storage
+        .put_file(key, &tmp.0)
+        .await
+        .map_err(storage_error)?;
+    if let Err(e) = cache::record(&*data.database.lock().await, key, size) {
+        warn!("record cached {} failed: {:?}", key, e);
+    }
     Ok(())
 }
 
+async fn find_meta(
+    name: &str,
+    version: &str,
+    data: &Server,
+) -> Result<IndexMetadata, DownloadError> {
+    data.index
+        .find(name, version)
+        .await
+        .map_err(|e| DownloadError::Index {
+            name: name.to_string(),
+            reason: format!("{:#}", e),
+        })?
+        .ok_or_else(|| DownloadError::NotInIndex {
+            name: name.to_string(),
+            version: version.to_string(),
+        })
+}
+
+async fn fetch_from_upstream(
+    key: &str,
+    name: &str,
+    version: &str,
+    data: &Arc<Server>,
+) -> Result<(), DownloadError> {
+    let meta = find_meta(name, version, data).await?;
+    let storage_error = |e: anyhow::Error| DownloadError::Storage {
+        key: key.to_string(),
+        reason: format!("{:#}", e),
+    };
+
+    // the last download of it may finish just before this one starts
+    if let Some(stored) = data.storage.get(key).await.map_err(storage_error)? {
+        if format!("{:x}", sha2::Sha256::digest(&stored)) == meta.cksum {
+            return Ok(());
+        }
+    }
+
+    let sources = data.sources.candidates(&data.config, name, version).await?;
+    let client = unsecure_http_client().map_err(|e| DownloadError::Http {
+        url: sources[0].url.clone(),
+        reason: format!("{:#}", e),
+    })?;
+
+    let mut mismatches = 0;
+    let mut error = None;
+    for round in 0..DOWNLOAD_ATTEMPTS {
+        if let Some(e) = &error {
+            let delay = Duration::from_millis(500 << (round - 1));
+            warn!("{}, retry in {:?}", e, delay);
+            time::sleep(delay).await;
+        }
+
+        // whether another round may succeed
+        let mut retry = false;
+        for source in &sources {
+            let result = match fetch_once(&client, source).await {
+                Ok(bytes) => {
+                    let checksum = format!("{:x}", sha2::Sha256::digest(&bytes));
+                    if checksum == meta.cksum {
+                        Ok(bytes)
+                    } else {
+                        mismatches += 1;
+                        quarantine(data, name, version, &checksum, Quarantined::Bytes(&bytes))
+                            .await;
+                        Err(DownloadError::ChecksumMismatch {
+                            key: key.to_string(),
+                            expected: meta.cksum.clone(),
+                            actual: checksum,
+                            attempts: mismatches,
+                        })
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let bytes = match result {
+                Ok(bytes) => {
+                    data.sources.succeeded(&source.source);
+                    bytes
+                }
+                Err(e) => {
+                    warn!("download {} from {} failed: {}", key, source.source, e);
+                    data.sources.failed(&source.source, &e);
+                    retry |= e.is_transient();
+                    error = Some(e);
+                    continue;
+                }
+            };
+
+            // the checksum matches, put it in place
+            data.storage
+                .put(key, bytes.to_vec())
+                .await
+                .map_err(storage_error)?;
+            if let Err(e) = cache::record(&*data.database.lock().await, key, bytes.len()) {
+                warn!("record cached {} failed: {:?}", key, e);
+            }
+            return Ok(());
+        }
+
+        if !retry {
+            break;
+        }
+    }
+
+    Err(error.expect("at least one attempt is made"))
+}
+
+async fn send(client: &Client, source: &Candidate) -> Result<Response, DownloadError> {
+    let response = client
+        .get(&source.url)
+        .timeout(source.timeout)
+        .send()
+        .await
+        .map_err(|e| DownloadError::Http {
+            url: source.url.clone(),
+            reason: e.to_string(),
+        })?;
+    if !response.status().is_success() {
+        return Err(DownloadError::UpstreamStatus {
+            url: source.url.clone(),
+            status: response.status().as_u16(),
+        });
+    }
+
+    Ok(response)
+}
+
+async fn fetch_once(client: &Client, source: &Candidate) -> Result<web::Bytes, DownloadError> {
+    let bytes = send(client, source)
+        .await?
+        .bytes()
+        .await
+        .map_err(|e| DownloadError::Http {
+            url: source.url.clone(),
+            reason: e.to_string(),
+        })?;
+    Ok(web::Bytes::from(bytes.to_vec()))
+}
+
+/// a crate file failed the checksum
+pub(super) enum Quarantined<'a> {
+    Bytes(&'a [u8]),
+    File(&'a Path),
+}
+
+/// keep a crate file failed the checksum in `crates.quarantine_path` for inspection, named with
+/// its checksum so the same bad file from retries is kept once
+async fn quarantine(
+    data: &Server,
+    name: &str,
+    version: &str,
+    checksum: &str,
+    file: Quarantined<'_>,
+) {
+    let dir = data.config.read().await.crates.quarantine_path.clone();
+    match quarantine_to(&dir, name, version, checksum, file).await {
+        Ok(path) => warn!(
+            "{}-{} failed verification, quarantined to {:?}",
+            name, version, path
+        ),
+        Err(e) => error!("quarantine {}-{} failed: {:?}", name, version, e),
+    }
+}
+
+/// copy a crate file failed the checksum into `dir`, return where it is
+pub(super) async fn quarantine_to(
+    dir: &Path,
+    name: &str,
+    version: &str,
+    checksum: &str,
+    file: Quarantined<'_>,
+) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("{}-{}-{}.crate", name, version, checksum));
+    match file {
+        Quarantined::Bytes(bytes) => fs::write(&path, bytes).await?,
+        Quarantined::File(file) => {
+            fs::copy(file, &path).await?;
+        }
+    }
+
+    Ok(path)
+}
+
 #[derive(Deserialize)]
 pub struct SearchParam {
     q: String,
@@ -260,52 +745,122 @@ async fn publish(
     mut body: web::Payload,
     data: web::Data<Server>,
 ) -> Result<HttpResponse> {
-    let db = data.database.lock().await;
-    let account = check_token(&db, req).map_err(|e| ErrorUnauthorized(e))?;
+    let limits = Limits::new(&*data.config.read().await);
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<usize>().ok());
+    if content_length.map_or(false, |l| l > limits.body()) {
+        return Err(ErrorPayloadTooLarge(format!(
+            "the request is larger than {} bytes",
+            limits.body()
+        )));
+    }
+
+    let account =
+        check_token(&*data.database.lock().await, req).map_err(|e| ErrorUnauthorized(e))?;
 
     let mut bytes = web::BytesMut::new();
     while let Some(b) = body.next().await {
-        bytes.extend_from_slice(&b?);
+        let b = b?;
+        if bytes.len() + b.len() > limits.body() {
+            return Err(ErrorPayloadTooLarge(format!(
+                "the request is larger than {} bytes",
+                limits.body()
+            )));
+        }
+        bytes.extend_from_slice(&b);
+    }
+
+    let (mut crate_info, crate_data) =
+        create_crate(&*bytes, &limits).map_err(|e| ErrorBadRequest(e))?;
+    let errors = validate::validate(&crate_info);
+    if !errors.is_empty() {
+        return Ok(publish_errors(errors));
+    }
+
+    let (files, errors) = tarball::inspect(&crate_info, &crate_data);
+    if !errors.is_empty() {
+        return Ok(publish_errors(errors));
+    }
+
+    let errors = deps::resolve(&data.config, &data.index, &mut crate_info.deps)
+        .await
+        .map_err(|e| ErrorInternalServerError(e))?;
+    if !errors.is_empty() {
+        return Ok(publish_errors(errors));
+    }
+
+    let claimed = shadow::claimed_by_upstream(
+        &data.config,
+        &data.git,
+        data.index.upstream(),
+        &crate_info.name,
+    )
+    .await
+    .map_err(|e| ErrorInternalServerError(e))?;
+
+    // the checks above may fetch from upstream, the database is not locked meanwhile
+    let db = data.database.lock().await;
+    if claimed
+        && !shadow::is_allowed(&db, &crate_info.name).map_err(|e| ErrorInternalServerError(e))?
+    {
+        return Err(ErrorForbidden(format!(
+            "crate name {} is already used by upstream, ask an admin to allow it if you really want",
+            crate_info.name
+        )));
     }
 
-    let (crate_info, crate_data) = create_crate(&*bytes).map_err(|e| ErrorBadRequest(e))?;
     if let Ok(old_crate) = db::get_crate(&db, &crate_info.name) {
         check_owner_impl(&account, old_crate.owners, &crate_info.name)
             .map_err(|e| ErrorForbidden(e))?;
     }
 
+    // check it before anything is changed, `Index::append` checks it again
+    let published = data
+        .index
+        .published(&crate_info.name)
+        .await
+        .map_err(|e| ErrorInternalServerError(e))?;
+    data.index
+        .check_new_version(&crate_info.name, &crate_info.vers)
+        .await
+        .map_err(|e| ErrorBadRequest(e))?;
+
     let cksum = format!("{:x}", sha2::Sha256::digest(&crate_data));
+    let warnings =
+        warnings::check_metadata(&db, &crate_info).map_err(|e| ErrorInternalServerError(e))?;
 
     // update database
-    db::update(&db, crate_info.clone(), &account.username)
+    db::update(&db, crate_info.clone(), &account.username, &published)
         .await
         .map_err(|e| ErrorInternalServerError(e))?;
 
     // store the crate data
     let crate_name = format!("{}-{}.crate", &crate_info.name, &crate_info.vers);
-    let crate_path = data
-        .config
-        .read()
+    let crate_size = crate_data.len();
+    data.storage
+        .put(&crate_key(&crate_info.name, &crate_info.vers), crate_data)
         .await
-        .crates
-        .storage_path
-        .join(&crate_info.name)
-        .join(&crate_name);
-    let dir = crate_path
-        .parent()
-        .ok_or(anyhow!("no parent for {:?}", crate_path))
         .map_err(|e| ErrorInternalServerError(e))?;
-    if !dir.exists() {
-        fs::create_dir_all(dir)?;
-    }
-    fs::write(crate_path, crate_data)?;
 
     // update work tree, and sync
     let commit_msg = format!("add crate {}-{}", &crate_info.name, &crate_info.vers);
     data.index
-        .append(crate_info, cksum)
+        .append(crate_info.clone(), cksum)
         .await
         .map_err(|e| ErrorBadRequest(e))?;
+
+    // only a version in the index has the details, the ones without are served from the index
+    let recorded = db.connection.transaction::<_, anyhow::Error, _>(|| {
+        detail::record(&db, &crate_info, crate_size, &account.username)?;
+        detail::record_files(&db, &crate_info, &files)
+    });
+    if let Err(e) = recorded {
+        warn!("record the details of {} failed: {:?}", crate_name, e);
+    }
+
     data.git
         .commit(commit_msg)
         .await
@@ -316,15 +871,30 @@ async fn publish(
         .map_err(|e| ErrorInternalServerError(e))?;
 
     info!("{} published new crate {}", account.username, &crate_name);
-    Ok(HttpResponse::Ok().json(quick_ok()))
+    Ok(HttpResponse::Ok().json(json!({ "warnings": warnings })))
 }
 
-fn create_crate(bytes: &[u8]) -> anyhow::Result<(CrateInfo, Vec<u8>)> {
+/// all the problems in one response, cargo prints them all
+fn publish_errors(errors: Vec<String>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "errors": errors.into_iter().map(|e| json!({ "detail": e })).collect::<Vec<_>>()
+    }))
+}
+
+/// the lengths are checked against `limits` before anything is allocated
+fn create_crate(bytes: &[u8], limits: &Limits) -> anyhow::Result<(CrateInfo, Vec<u8>)> {
     let mut reader = BufReader::new(bytes);
 
     let mut metadata_len_bytes = [0u8; 4];
     reader.read_exact(&mut metadata_len_bytes)?;
     let metadata_len = u32::from_le_bytes(metadata_len_bytes);
+    if metadata_len as usize > limits.metadata {
+        bail!(
+            "the metadata is {} bytes, larger than the limit {} bytes",
+            metadata_len,
+            limits.metadata
+        );
+    }
 
     let mut metadata = vec![0u8; metadata_len as usize];
     reader.read_exact(&mut metadata)?;
@@ -333,6 +903,13 @@ fn create_crate(bytes: &[u8]) -> anyhow::Result<(CrateInfo, Vec<u8>)> {
     let mut crate_len_bytes = [0u8; 4];
     reader.read_exact(&mut crate_len_bytes)?;
     let crate_len = u32::from_le_bytes(crate_len_bytes);
+    if crate_len as usize > limits.crate_file {
+        bail!(
+            "the crate file is {} bytes, larger than the limit {} bytes",
+            crate_len,
+            limits.crate_file
+        );
+    }
 
     let mut crate_data = vec![0u8; crate_len as usize];
     reader.read_exact(&mut crate_data)?;
@@ -18,9 +18,8 @@ pub struct Crates {
     pub categories: Option<String>,
     #[serde(skip_serializing)]
     pub created_at: String,
-    #[serde(skip_serializing)]
     pub downloads: i32,
-    #[serde(skip_serializing)]
+    /// the downloads in the last 90 days
     pub recent_downloads: i32,
     pub max_version: String,
     #[serde(skip_serializing)]
@@ -99,31 +98,72 @@ pub struct Dependency {
     /// If the dependency is renamed from the original package name,
     /// this is the original name. The new package name is stored in
     /// the `explicit_name_in_toml` field
-    name: String,
+    pub name: String,
     #[serde(alias = "req")]
     /// The semver requirement for the dependency
-    version_req: String,
+    pub version_req: String,
     /// Array of features (as string) enabled for this dependency
-    features: Vec<String>,
+    pub features: Vec<String>,
     /// Boolean of whether or not this is an optional dependency
-    optional: bool,
+    pub optional: bool,
     /// Boolean of whether or not default features are enabled
-    default_features: bool,
+    pub default_features: bool,
     /// The target platform for the dependency
     /// null if not a target dependency
     /// Otherwise, a string such as "cfg(windows)"
-    target: Option<String>,
+    pub target: Option<String>,
     /// The dependency kind.
     /// "dev", "buiild", or "normal"
-    kind: Option<String>,
+    pub kind: Option<String>,
     /// The URL of the index of the registry where this dependency is
     /// from as string. If not specified or null, it is assumed the
     /// dependency is in the current registry
-    registry: Option<String>,
+    pub registry: Option<String>,
     /// If the dependency is renamed, this is a string of the new
     /// package name. If not specified or null, this dependency is not
     /// renamed.
-    explicit_name_in_toml: Option<String>,
+    pub explicit_name_in_toml: Option<String>,
+}
+
+/// a dependency in index, see https://doc.rust-lang.org/cargo/reference/registries.html#index-format
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexDependency {
+    /// the name used in `Cargo.toml`, it's the renamed one if renamed
+    pub name: String,
+    /// `version_req` in the entries written by the older versions
+    #[serde(alias = "version_req")]
+    pub req: String,
+    pub features: Vec<String>,
+    pub optional: bool,
+    pub default_features: bool,
+    pub target: Option<String>,
+    pub kind: Option<String>,
+    /// the index url of the registry, `None` for this one
+    pub registry: Option<String>,
+    /// the original name if renamed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+}
+
+impl From<Dependency> for IndexDependency {
+    fn from(dep: Dependency) -> Self {
+        let (name, package) = match dep.explicit_name_in_toml {
+            Some(renamed) => (renamed, Some(dep.name)),
+            None => (dep.name, None),
+        };
+
+        IndexDependency {
+            name,
+            req: dep.version_req,
+            features: dep.features,
+            optional: dep.optional,
+            default_features: dep.default_features,
+            target: dep.target,
+            kind: dep.kind,
+            registry: dep.registry,
+            package,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -131,7 +171,7 @@ pub struct Dependency {
 pub struct IndexMetadata {
     pub name: String,
     pub vers: String,
-    pub deps: Vec<Dependency>,
+    pub deps: Vec<IndexDependency>,
     pub cksum: String,
     pub features: HashMap<String, Vec<String>>,
     pub yanked: bool,
@@ -0,0 +1,369 @@
+use super::{download_from_upstream, Index};
+use crate::{auth::check, storage::crate_key, Server};
+use anyhow::{anyhow, bail, Result};
+use chrono::Local;
+use futures::StreamExt;
+use log::{info, warn};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spa_server::re_export::{
+    error::{ErrorBadRequest, ErrorConflict},
+    get, post, web, HttpResponse, Identity, Responder,
+};
+use std::sync::Arc;
+
+#[cfg(test)]
+mod test {
+    use super::{lock_targets, parse_target, Target};
+    use semver::VersionReq;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target("serde@1.0").unwrap(),
+            Target::Matches("serde".to_string(), VersionReq::parse("1.0").unwrap())
+        );
+        assert_eq!(
+            parse_target("tokio").unwrap(),
+            Target::Matches("tokio".to_string(), VersionReq::any())
+        );
+        assert!(parse_target("serde@foo").is_err());
+        assert!(parse_target("@1.0").is_err());
+    }
+
+    #[test]
+    fn test_lock_targets() {
+        let lock = r#"
+version = 3
+
+[[package]]
+name = "local"
+version = "0.1.0"
+dependencies = ["serde"]
+
+[[package]]
+name = "serde"
+version = "1.0.125"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "558dc50e1a5a5fa7112ca2ce4effcb321b0300c0d4ccf0776a9f60cd89031171"
+
+[[package]]
+name = "forked"
+version = "0.2.0"
+source = "git+https://github.com/someone/forked#0123456789abcdef"
+"#;
+        assert_eq!(
+            lock_targets(lock).unwrap(),
+            vec![Target::Exact("serde".to_string(), "1.0.125".to_string())]
+        );
+    }
+}
+
+/// a crate to prefetch
+#[derive(Debug, PartialEq)]
+pub(crate) enum Target {
+    /// the exact version, eg. from `Cargo.lock`
+    Exact(String, String),
+    /// the newest version matches the requirement, eg. `serde@1.0`
+    Matches(String, VersionReq),
+    /// all the versions not yanked
+    All(String),
+}
+
+impl Target {
+    fn name(&self) -> &str {
+        match self {
+            Target::Exact(name, _) | Target::Matches(name, _) | Target::All(name) => name,
+        }
+    }
+}
+
+/// parse `name@req`, a bare `name` means the newest version
+fn parse_target(s: &str) -> Result<Target> {
+    let mut parts = s.trim().splitn(2, '@');
+    let name = parts.next().unwrap_or_default();
+    if name.is_empty() {
+        bail!("no crate name in {}", s);
+    }
+
+    let req = match parts.next() {
+        Some(req) => VersionReq::parse(req).map_err(|e| anyhow!("invalid {}: {}", s, e))?,
+        None => VersionReq::any(),
+    };
+    Ok(Target::Matches(name.to_string(), req))
+}
+
+#[derive(Deserialize)]
+struct LockFile {
+    #[serde(default)]
+    package: Vec<LockPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+}
+
+/// the registry packages in a `Cargo.lock`, the path and git ones are skipped
+fn lock_targets(content: &str) -> Result<Vec<Target>> {
+    let lock: LockFile = toml::from_str(content)?;
+    Ok(lock
+        .package
+        .into_iter()
+        .filter(|p| match &p.source {
+            Some(s) => s.starts_with("registry+") || s.starts_with("sparse+"),
+            None => false,
+        })
+        .map(|p| Target::Exact(p.name, p.version))
+        .collect())
+}
+
+/// what to prefetch, eg.
+/// `{"lockfile": "<content of Cargo.lock>", "crates": ["serde@1.0"], "all_versions": ["rand"]}`
+#[derive(Deserialize, Default)]
+pub(crate) struct PrefetchRequest {
+    pub lockfile: Option<String>,
+    #[serde(default)]
+    pub crates: Vec<String>,
+    #[serde(default)]
+    pub all_versions: Vec<String>,
+    /// the number of concurrent downloads, default is 8
+    pub jobs: Option<usize>,
+}
+
+impl PrefetchRequest {
+    pub(crate) fn targets(&self) -> Result<Vec<Target>> {
+        let mut targets = match &self.lockfile {
+            Some(lock) => lock_targets(lock)?,
+            None => Vec::new(),
+        };
+        for c in &self.crates {
+            targets.push(parse_target(c)?);
+        }
+        targets.extend(self.all_versions.iter().map(|n| Target::All(n.clone())));
+
+        Ok(targets)
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct PrefetchFailure {
+    name: String,
+    version: Option<String>,
+    error: String,
+}
+
+/// progress of the running prefetch, or the result of the last one
+#[derive(Serialize, Clone)]
+pub(crate) struct PrefetchProgress {
+    started_at: String,
+    finished_at: Option<String>,
+    total: usize,
+    cached: usize,
+    downloaded: usize,
+    failures: Vec<PrefetchFailure>,
+}
+
+impl PrefetchProgress {
+    pub(crate) fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// mark a prefetch as running, false if there is one running already
+pub(crate) fn begin_prefetch(data: &Server) -> bool {
+    let mut state = data.prefetch.lock().unwrap();
+    if let Some(progress) = state.as_ref() {
+        if progress.finished_at.is_none() {
+            return false;
+        }
+    }
+
+    *state = Some(PrefetchProgress {
+        started_at: Local::now().to_string(),
+        finished_at: None,
+        total: 0,
+        cached: 0,
+        downloaded: 0,
+        failures: Vec::new(),
+    });
+    true
+}
+
+fn update(data: &Server, f: impl FnOnce(&mut PrefetchProgress)) {
+    if let Some(progress) = data.prefetch.lock().unwrap().as_mut() {
+        f(progress);
+    }
+}
+
+/// download the targets from upstream into our storage, `jobs` at a time, the cached ones
+/// are verified against the index and downloaded again if they do not match.
+/// call `begin_prefetch` first.
+pub(crate) async fn prefetch(
+    data: &Arc<Server>,
+    targets: Vec<Target>,
+    jobs: usize,
+) -> Option<PrefetchProgress> {
+    let mut crates = Vec::new();
+    for target in targets {
+        let name = target.name().to_string();
+        match resolve(&data.index, target).await {
+            Ok(versions) => crates.extend(versions),
+            Err(e) => update(data, |p| {
+                p.failures.push(PrefetchFailure {
+                    name,
+                    version: None,
+                    error: format!("{:?}", e),
+                })
+            }),
+        }
+    }
+    crates.sort();
+    crates.dedup();
+    let total = crates.len();
+    update(data, |p| p.total = total);
+
+    let mut done = 0;
+    let mut results = futures::stream::iter(crates)
+        .map(|(name, version)| async move {
+            let result = fetch(data, &name, &version)
+                .await
+                .map(|(_, downloaded)| downloaded);
+            (name, version, result)
+        })
+        .buffer_unordered(jobs.max(1));
+    while let Some((name, version, result)) = results.next().await {
+        done += 1;
+        match result {
+            Ok(downloaded) => {
+                info!(
+                    "prefetch [{}/{}] {}-{} {}",
+                    done,
+                    total,
+                    name,
+                    version,
+                    if downloaded { "downloaded" } else { "cached" }
+                );
+                update(data, |p| {
+                    if downloaded {
+                        p.downloaded += 1;
+                    } else {
+                        p.cached += 1;
+                    }
+                });
+            }
+            Err(e) => {
+                warn!(
+                    "prefetch [{}/{}] {}-{} failed: {:?}",
+                    done, total, name, version, e
+                );
+                update(data, |p| {
+                    p.failures.push(PrefetchFailure {
+                        name,
+                        version: Some(version),
+                        error: format!("{:?}", e),
+                    })
+                });
+            }
+        }
+    }
+
+    update(data, |p| p.finished_at = Some(Local::now().to_string()));
+    data.prefetch.lock().unwrap().clone()
+}
+
+/// the name and version pairs of a target
+pub(super) async fn resolve(index: &Index, target: Target) -> Result<Vec<(String, String)>> {
+    let (name, req) = match target {
+        Target::Exact(name, version) => return Ok(vec![(name, version)]),
+        Target::Matches(name, req) => (name, Some(req)),
+        Target::All(name) => (name, None),
+    };
+
+    let versions = index
+        .versions(&name)
+        .await?
+        .into_iter()
+        .filter(|m| !m.yanked)
+        .filter_map(|m| Version::parse(&m.vers).ok().map(|v| (v, m.vers)));
+    match req {
+        Some(req) => versions
+            .filter(|(v, _)| req.matches(v))
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map(|(_, vers)| vec![(name.clone(), vers)])
+            .ok_or(anyhow!("no version of {} matches {}", name, req)),
+        None => Ok(versions.map(|(_, vers)| (name.clone(), vers)).collect()),
+    }
+}
+
+/// the crate file verified against the index, and true if downloaded, false if cached already
+pub(super) async fn fetch(
+    data: &Arc<Server>,
+    name: &str,
+    version: &str,
+) -> Result<(web::Bytes, bool)> {
+    let key = crate_key(name, version);
+    let meta = data.index.get_exact(name, version).await?;
+    if let Some(stored) = data.storage.get(&key).await? {
+        if format!("{:x}", Sha256::digest(&stored)) == meta.cksum {
+            return Ok((web::Bytes::from(stored), false));
+        }
+
+        warn!("{} does not match the index, download it again", key);
+    }
+
+    download_from_upstream(&key, name, version, data).await?;
+    let bytes = data
+        .storage
+        .get(&key)
+        .await?
+        .ok_or_else(|| anyhow!("{} is gone just after downloaded", key))?;
+    Ok((web::Bytes::from(bytes), true))
+}
+
+/// start a prefetch in background, see `PrefetchRequest` for the body
+#[post("prefetch")]
+pub(crate) async fn start_prefetch(
+    mut body: web::Payload,
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    if !check(&id, &*data.database.lock().await)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    // a Cargo.lock is easily larger than the default json limit
+    let mut bytes = web::BytesMut::new();
+    while let Some(b) = body.next().await {
+        bytes.extend_from_slice(&b?);
+    }
+    let request: PrefetchRequest = serde_json::from_slice(&bytes).map_err(ErrorBadRequest)?;
+    let targets = request.targets().map_err(ErrorBadRequest)?;
+    if !begin_prefetch(&data) {
+        return Err(ErrorConflict("a prefetch is running"));
+    }
+
+    let data = data.into_inner();
+    let jobs = request.jobs.unwrap_or(8);
+    tokio::spawn(async move {
+        prefetch(&data, targets, jobs).await;
+    });
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// the progress of the running prefetch, or the result of the last one
+#[get("prefetch")]
+pub(crate) async fn prefetch_status(
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    if !check(&id, &*data.database.lock().await)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let progress = data.prefetch.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(progress))
+}
@@ -0,0 +1,201 @@
+use super::{db, relative_path, upstream::UpstreamIndex};
+use crate::{
+    auth::check,
+    config::Config,
+    database::{schema::shadow_overrides, Database},
+    git::Git,
+    Server,
+};
+use anyhow::Result;
+use chrono::Local;
+use diesel::prelude::*;
+use log::info;
+use serde::Serialize;
+use serde_json::json;
+use spa_server::re_export::{
+    delete, error::ErrorInternalServerError, get, put, web, HttpResponse, Identity, Responder,
+};
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+#[cfg(test)]
+mod test {
+    use super::upstream_names;
+
+    #[test]
+    fn test_upstream_names() {
+        assert_eq!(upstream_names("serde"), vec!["serde"]);
+        assert_eq!(
+            upstream_names("Tokio-Util"),
+            vec!["tokio-util", "tokio_util"]
+        );
+        assert_eq!(upstream_names("a_b-c"), vec!["a_b-c", "a_b_c", "a-b-c"]);
+    }
+}
+
+/// a private crate name allowed by admin to shadow the upstream one
+#[derive(Queryable, Insertable, Serialize)]
+#[table_name = "shadow_overrides"]
+pub(crate) struct ShadowOverride {
+    name: String,
+    allowed_by: String,
+    created_at: String,
+}
+
+/// the names collide with `name` on upstream, crates.io takes `-` and `_` as the same
+fn upstream_names(name: &str) -> Vec<String> {
+    let name = name.to_lowercase();
+    let mut names = vec![name.clone()];
+    for n in [name.replace('-', "_"), name.replace('_', "-")].iter() {
+        if !names.contains(n) {
+            names.push(n.clone());
+        }
+    }
+
+    names
+}
+
+/// whether upstream index has a crate named `name`
+pub(crate) async fn claimed_by_upstream(
+    config: &Arc<RwLock<Config>>,
+    git: &Git,
+    upstream: &UpstreamIndex,
+    name: &str,
+) -> Result<bool> {
+    let lazy = config.read().await.git.upstream_sparse_url.is_some();
+    for n in upstream_names(name) {
+        let path = relative_path(&n);
+        let found = if lazy {
+            upstream.get(&path).await?.is_some()
+        } else {
+            git.upstream_has(&path).await
+        };
+
+        if found {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// the private crates claimed by upstream as well, except the ones allowed by admin
+pub(crate) async fn shadowed_names(
+    config: &Arc<RwLock<Config>>,
+    git: &Git,
+    upstream: &UpstreamIndex,
+    database: &Mutex<Database>,
+) -> Result<Vec<String>> {
+    let names = {
+        let db = database.lock().await;
+        let allowed = allowed_names(&db)?;
+        db::private_names(&db)?
+            .into_iter()
+            .filter(|n| !allowed.contains(n))
+            .collect::<Vec<_>>()
+    };
+
+    let mut shadowed = Vec::new();
+    for n in names {
+        if claimed_by_upstream(config, git, upstream, &n).await? {
+            shadowed.push(n);
+        }
+    }
+
+    Ok(shadowed)
+}
+
+pub(super) fn is_allowed(db: &Database, crate_name: &str) -> Result<bool> {
+    Ok(allowed_names(db)?.iter().any(|n| n == crate_name))
+}
+
+fn allowed_names(db: &Database) -> Result<Vec<String>> {
+    Ok(shadow_overrides::table
+        .select(shadow_overrides::name)
+        .load::<String>(&db.connection)?)
+}
+
+/// the allowed names, and the private crates shadowed by upstream now
+#[get("shadow")]
+pub(crate) async fn list_shadow(
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    let allowed = {
+        let db = data.database.lock().await;
+        if !check(&id, &db)?.is_admin() {
+            return Ok(HttpResponse::Forbidden().finish());
+        }
+
+        shadow_overrides::table
+            .load::<ShadowOverride>(&db.connection)
+            .map_err(|e| ErrorInternalServerError(format!("load allowed names failed: {:?}", e)))?
+    };
+
+    let shadowed = shadowed_names(
+        &data.config,
+        &data.git,
+        data.index.upstream(),
+        &data.database,
+    )
+    .await
+    .map_err(|e| ErrorInternalServerError(format!("check shadowed names failed: {:?}", e)))?;
+    Ok(HttpResponse::Ok().json(json!({
+        "allowed": allowed,
+        "shadowed": shadowed,
+    })))
+}
+
+/// allow publishing a private crate named `name` even though upstream has it
+#[put("shadow/{name}")]
+pub(crate) async fn allow_shadow(
+    info: web::Path<String>,
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    let db = data.database.lock().await;
+    let account = check(&id, &db)?;
+    if !account.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let name = info.into_inner();
+    diesel::replace_into(shadow_overrides::table)
+        .values(ShadowOverride {
+            name: name.clone(),
+            allowed_by: account.username.clone(),
+            created_at: Local::now().to_string(),
+        })
+        .execute(&db.connection)
+        .map_err(|e| ErrorInternalServerError(format!("allow {} failed: {:?}", name, e)))?;
+
+    info!(
+        "{} allowed crate {} to shadow upstream",
+        account.username, name
+    );
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("shadow/{name}")]
+pub(crate) async fn disallow_shadow(
+    info: web::Path<String>,
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    let db = data.database.lock().await;
+    let account = check(&id, &db)?;
+    if !account.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let name = info.into_inner();
+    diesel::delete(shadow_overrides::table.find(&name))
+        .execute(&db.connection)
+        .map_err(|e| ErrorInternalServerError(format!("disallow {} failed: {:?}", name, e)))?;
+
+    info!(
+        "{} disallowed crate {} to shadow upstream",
+        account.username, name
+    );
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,178 @@
+use super::error::DownloadError;
+use crate::{auth::check, config::Config, storage::crate_key, Server};
+use serde::Serialize;
+use spa_server::re_export::{get, web, HttpResponse, Identity, Responder};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+/// a source is down after failing this many times in a row
+const FAILURES_TO_DOWN: u32 = 3;
+/// a source down is skipped for this long, unless all the others are down too
+const DOWN_TIME: Duration = Duration::from_secs(60);
+/// the timeout of `crates.upstream_url`
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[cfg(test)]
+mod test {
+    use super::download_url;
+
+    #[test]
+    fn test_download_url() {
+        assert_eq!(
+            download_url("https://crates.io/", "serde", "1.0.0"),
+            "https://crates.io/api/v1/crates/serde/1.0.0/download"
+        );
+        assert_eq!(
+            download_url(
+                "https://static.crates.io/crates/{crate}/{crate}-{version}.crate",
+                "serde",
+                "1.0.0"
+            ),
+            "https://static.crates.io/crates/serde/serde-1.0.0.crate"
+        );
+    }
+}
+
+/// the download url of a crate from a source, see `config::Source`
+fn download_url(source: &str, name: &str, version: &str) -> String {
+    if source.contains("{crate}") || source.contains("{version}") {
+        source
+            .replace("{crate}", name)
+            .replace("{version}", version)
+    } else {
+        format!(
+            "{}/api/v1/crates/{}/{}/download",
+            source.trim_end_matches('/'),
+            name,
+            version
+        )
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    down_until: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl Health {
+    fn is_down(&self) -> bool {
+        self.down_until.map_or(false, |t| t > Instant::now())
+    }
+}
+
+/// where to download a crate
+pub(super) struct Candidate {
+    pub source: String,
+    pub url: String,
+    pub timeout: Duration,
+}
+
+/// the health of the crate download sources, keyed by their url
+#[derive(Default)]
+pub(crate) struct Sources {
+    health: std::sync::Mutex<HashMap<String, Health>>,
+}
+
+#[derive(Serialize)]
+struct SourceStatus {
+    url: String,
+    timeout_secs: u64,
+    down: bool,
+    failures: u32,
+    last_error: Option<String>,
+}
+
+/// `crates.sources`, or `crates.upstream_url` if there is none
+fn configured(config: &Config) -> Vec<(String, Duration)> {
+    if config.crates.sources.is_empty() {
+        return vec![(config.crates.upstream_url.clone(), DEFAULT_TIMEOUT)];
+    }
+
+    config
+        .crates
+        .sources
+        .iter()
+        .map(|s| (s.url.clone(), Duration::from_secs(s.timeout_secs)))
+        .collect()
+}
+
+impl Sources {
+    /// the sources to download a crate from, the ones up first in the configured order,
+    /// then the ones down as the last resort
+    pub(super) async fn candidates(
+        &self,
+        config: &RwLock<Config>,
+        name: &str,
+        version: &str,
+    ) -> Result<Vec<Candidate>, DownloadError> {
+        let cfg = config.read().await;
+        if cfg.registry.offline {
+            return Err(DownloadError::Offline {
+                key: crate_key(name, version),
+            });
+        }
+
+        let health = self.health.lock().unwrap();
+        let (up, down): (Vec<_>, Vec<_>) = configured(&cfg)
+            .into_iter()
+            .map(|(source, timeout)| Candidate {
+                url: download_url(&source, name, version),
+                source,
+                timeout,
+            })
+            .partition(|c| !health.get(&c.source).map_or(false, |h| h.is_down()));
+        Ok(up.into_iter().chain(down).collect())
+    }
+
+    pub(super) fn succeeded(&self, source: &str) {
+        self.health.lock().unwrap().remove(source);
+    }
+
+    /// only the errors may go away by themselves count, eg. timeout or 503
+    pub(super) fn failed(&self, source: &str, error: &DownloadError) {
+        if !error.is_transient() {
+            return;
+        }
+
+        let mut health = self.health.lock().unwrap();
+        let h = health.entry(source.to_string()).or_default();
+        h.failures += 1;
+        h.last_error = Some(error.to_string());
+        if h.failures >= FAILURES_TO_DOWN {
+            h.down_until = Some(Instant::now() + DOWN_TIME);
+        }
+    }
+}
+
+/// the crate download sources in order and their health
+#[get("sources")]
+pub(crate) async fn list_sources(
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    if !check(&id, &*data.database.lock().await)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let configured = configured(&*data.config.read().await);
+    let health = data.sources.health.lock().unwrap();
+    let status = configured
+        .into_iter()
+        .map(|(url, timeout)| {
+            let h = health.get(&url);
+            SourceStatus {
+                down: h.map_or(false, |h| h.is_down()),
+                failures: h.map_or(0, |h| h.failures),
+                last_error: h.and_then(|h| h.last_error.clone()),
+                timeout_secs: timeout.as_secs(),
+                url,
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(status))
+}
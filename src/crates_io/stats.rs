@@ -0,0 +1,176 @@
+use crate::{
+    auth::check_registry_token,
+    database::{
+        schema::{crates, version_downloads},
+        Database,
+    },
+    Server,
+};
+use anyhow::Result;
+use chrono::{Duration, NaiveDate, Utc};
+use diesel::{dsl, prelude::*, sql_types::Date};
+use log::{error, info};
+use serde::Serialize;
+use spa_server::{
+    error_to_json,
+    re_export::{
+        error::ErrorInternalServerError, get, web, HttpRequest, HttpResponse, Result as WebResult,
+    },
+};
+use std::{collections::HashMap, sync::Arc};
+use tokio::{sync::Mutex, time};
+
+/// `recent_downloads` counts the downloads in this many days
+const RECENT_DAYS: i64 = 90;
+/// how often `recent_downloads` of all the crates are refreshed in background
+const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// the downloads of a version in a day
+#[derive(Queryable, Insertable, Serialize)]
+#[table_name = "version_downloads"]
+struct VersionDownload {
+    #[serde(skip)]
+    name: String,
+    version: String,
+    date: NaiveDate,
+    downloads: i32,
+}
+
+#[derive(Serialize)]
+struct DownloadsMeta {
+    downloads: i32,
+    recent_downloads: i32,
+}
+
+#[derive(Serialize)]
+struct CrateDownloads {
+    version_downloads: Vec<VersionDownload>,
+    meta: DownloadsMeta,
+}
+
+fn recent_cutoff() -> NaiveDate {
+    Utc::today().naive_utc() - Duration::days(RECENT_DAYS - 1)
+}
+
+/// count a download of a crate, `downloads` and `recent_downloads` of the crate are updated
+/// if it is in the `crates` table
+pub(super) fn record(db: &Database, name: &str, version: &str) -> Result<()> {
+    let today = Utc::today().naive_utc();
+    db.connection.transaction::<_, anyhow::Error, _>(|| {
+        let updated = diesel::update(version_downloads::table.find((name, version, today)))
+            .set(version_downloads::downloads.eq(version_downloads::downloads + 1))
+            .execute(&db.connection)?;
+        if updated == 0 {
+            diesel::insert_into(version_downloads::table)
+                .values(VersionDownload {
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    date: today,
+                    downloads: 1,
+                })
+                .execute(&db.connection)?;
+        }
+
+        let recent = version_downloads::table
+            .filter(version_downloads::name.eq(name))
+            .filter(version_downloads::date.ge(recent_cutoff()))
+            .select(dsl::sum(version_downloads::downloads))
+            .first::<Option<i64>>(&db.connection)?
+            .unwrap_or_default();
+        diesel::update(crates::table.filter(crates::name.eq(name)))
+            .set((
+                crates::downloads.eq(crates::downloads + 1),
+                crates::recent_downloads.eq(recent as i32),
+            ))
+            .execute(&db.connection)?;
+        Ok(())
+    })
+}
+
+/// the total downloads of each version of a crate
+pub(super) fn version_totals(db: &Database, name: &str) -> Result<HashMap<String, i64>> {
+    let mut totals = HashMap::new();
+    for (version, downloads) in version_downloads::table
+        .filter(version_downloads::name.eq(name))
+        .select((version_downloads::version, version_downloads::downloads))
+        .load::<(String, i32)>(&db.connection)?
+    {
+        *totals.entry(version).or_default() += downloads as i64;
+    }
+
+    Ok(totals)
+}
+
+/// recount `recent_downloads` of all the crates, the days out of the window are dropped
+fn refresh_recent(db: &Database) -> Result<usize> {
+    Ok(diesel::sql_query(
+        "UPDATE crates SET recent_downloads = COALESCE((SELECT SUM(v.downloads) \
+         FROM version_downloads v WHERE v.name = crates.name AND v.date >= ?), 0)",
+    )
+    .bind::<Date, _>(recent_cutoff())
+    .execute(&db.connection)?)
+}
+
+/// refresh `recent_downloads` now and then every day in background
+pub(crate) fn schedule_refresh(database: Arc<Mutex<Database>>) {
+    let _ = tokio::spawn(async move {
+        loop {
+            match refresh_recent(&*database.lock().await) {
+                Ok(n) => info!("refreshed recent downloads of {} crates", n),
+                Err(e) => error!("refresh recent downloads failed: {:?}", e),
+            }
+            time::sleep(REFRESH_INTERVAL).await;
+        }
+    });
+}
+
+/// the daily downloads of every version in the last 90 days, and the totals of the crate
+#[error_to_json]
+#[get("{crate_name}/downloads")]
+pub async fn crate_downloads(
+    req: HttpRequest,
+    data: web::Data<Server>,
+    info: web::Path<(String,)>,
+) -> WebResult<HttpResponse> {
+    check_registry_token(&req, &data).await?;
+
+    let (crate_name,) = info.into_inner();
+    let db = data.database.lock().await;
+    let days = version_downloads::table
+        .filter(version_downloads::name.eq(&crate_name))
+        .filter(version_downloads::date.ge(recent_cutoff()))
+        .order((
+            version_downloads::date.desc(),
+            version_downloads::version.asc(),
+        ))
+        .load::<VersionDownload>(&db.connection)
+        .map_err(ErrorInternalServerError)?;
+    let counters = crates::table
+        .filter(crates::name.eq(&crate_name))
+        .select((crates::downloads, crates::recent_downloads))
+        .first::<(i32, i32)>(&db.connection)
+        .optional()
+        .map_err(ErrorInternalServerError)?;
+    let (downloads, recent_downloads) = match counters {
+        Some(counters) => counters,
+        None => {
+            // not in the `crates` table, eg. an upstream crate never searched
+            let total = version_downloads::table
+                .filter(version_downloads::name.eq(&crate_name))
+                .select(dsl::sum(version_downloads::downloads))
+                .first::<Option<i64>>(&db.connection)
+                .map_err(ErrorInternalServerError)?
+                .unwrap_or_default();
+            let recent = days.iter().map(|v| v.downloads).sum();
+            (total as i32, recent)
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(CrateDownloads {
+        version_downloads: days,
+        meta: DownloadsMeta {
+            downloads,
+            recent_downloads,
+        },
+    }))
+}
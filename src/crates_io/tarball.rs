@@ -0,0 +1,384 @@
+use super::models::{CrateInfo, Dependency};
+use anyhow::{bail, Result};
+use flate2::read::GzDecoder;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Component, Path},
+};
+use tar::{Archive, EntryType};
+
+/// the most bytes a crate file may unpack to, against the gzip bombs
+const MAX_UNPACKED: u64 = 512 * 1024 * 1024;
+
+#[cfg(test)]
+mod test {
+    use super::{escapes, inspect};
+    use crate::crates_io::models::CrateInfo;
+    use flate2::{write::GzEncoder, Compression};
+    use std::path::Path;
+    use tar::{Builder, EntryType, Header};
+
+    #[test]
+    fn test_escapes() {
+        let root = Path::new("foo-1.0.0");
+        assert!(!escapes(root, Path::new("foo-1.0.0/src/lib.rs")));
+        assert!(!escapes(root, Path::new("foo-1.0.0/src/../README.md")));
+        assert!(escapes(root, Path::new("foo-1.0.0/../bar-1.0.0/lib.rs")));
+        assert!(escapes(root, Path::new("/etc/passwd")));
+        assert!(escapes(root, Path::new("bar-1.0.0/lib.rs")));
+    }
+
+    fn append(builder: &mut Builder<GzEncoder<Vec<u8>>>, path: &str, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    #[test]
+    fn test_inspect() {
+        let info: CrateInfo = serde_json::from_str(
+            r#"{
+                "name": "foo", "vers": "1.0.0", "features": {}, "authors": [],
+                "deps": [{
+                    "name": "serde", "version_req": "^1.0", "features": [], "optional": false,
+                    "default_features": true, "target": null, "kind": "normal",
+                    "registry": null, "explicit_name_in_toml": null
+                }],
+                "description": null, "documentation": null, "homepage": null, "readme": null,
+                "readme_file": null, "keywords": [], "categories": [], "license": null,
+                "license_file": null, "repository": null, "badges": {}, "links": null
+            }"#,
+        )
+        .unwrap();
+
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        append(
+            &mut builder,
+            "foo-1.0.0/Cargo.toml",
+            b"[package]\nname = \"foo\"\nversion = \"1.0.0\"\n\n\
+              [dependencies.serde]\nversion = \"1.0\"\n",
+        );
+        append(&mut builder, "foo-1.0.0/src/lib.rs", b"");
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "foo-1.0.0/secret", "../../etc/passwd")
+            .unwrap();
+        let data = builder.into_inner().unwrap().finish().unwrap();
+
+        let (files, errors) = inspect(&info, &data);
+        assert_eq!(files.len(), 2);
+        assert_eq!(errors.len(), 1);
+
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        append(
+            &mut builder,
+            "foo-1.0.0/Cargo.toml",
+            b"[package]\nname = \"bar\"\nversion = \"1.0.0\"\n",
+        );
+        let data = builder.into_inner().unwrap().finish().unwrap();
+        let (_, errors) = inspect(&info, &data);
+        // the name and the missing serde
+        assert_eq!(errors.len(), 2);
+    }
+}
+
+/// a file in a crate file, the path is relative to the `name-version/` directory
+#[derive(Queryable, Serialize)]
+pub(super) struct CrateFile {
+    pub path: String,
+    pub size: i64,
+}
+
+/// `Cargo.toml` normalized by `cargo package`, only what is checked against the metadata
+#[derive(Deserialize)]
+struct Manifest {
+    package: Package,
+    #[serde(flatten)]
+    deps: ManifestDeps,
+    #[serde(default)]
+    target: BTreeMap<String, ManifestDeps>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct ManifestDeps {
+    #[serde(default)]
+    dependencies: BTreeMap<String, ManifestDep>,
+    #[serde(default, rename = "dev-dependencies", alias = "dev_dependencies")]
+    dev_dependencies: BTreeMap<String, ManifestDep>,
+    #[serde(default, rename = "build-dependencies", alias = "build_dependencies")]
+    build_dependencies: BTreeMap<String, ManifestDep>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ManifestDep {
+    Simple(String),
+    Detailed {
+        version: Option<String>,
+        package: Option<String>,
+    },
+}
+
+/// a dependency is keyed by its name in `Cargo.toml`, kind and target
+type DepKey = (String, String, Option<String>);
+
+impl Manifest {
+    /// the dependencies with a version, the others are not published
+    fn deps(&self) -> BTreeMap<DepKey, (String, String)> {
+        let targets = self
+            .target
+            .iter()
+            .map(|(t, deps)| (Some(t.clone()), deps))
+            .chain(std::iter::once((None, &self.deps)));
+
+        let mut all = BTreeMap::new();
+        for (target, deps) in targets {
+            for (kind, deps) in &[
+                ("normal", &deps.dependencies),
+                ("dev", &deps.dev_dependencies),
+                ("build", &deps.build_dependencies),
+            ] {
+                for (name, dep) in deps.iter() {
+                    let (package, req) = match dep {
+                        ManifestDep::Simple(req) => (None, req),
+                        ManifestDep::Detailed {
+                            version: Some(req),
+                            package,
+                        } => (package.as_ref(), req),
+                        ManifestDep::Detailed { version: None, .. } => continue,
+                    };
+                    all.insert(
+                        (name.clone(), kind.to_string(), target.clone()),
+                        (package.unwrap_or(name).clone(), req.clone()),
+                    );
+                }
+            }
+        }
+
+        all
+    }
+}
+
+fn dep_key(dep: &Dependency) -> DepKey {
+    (
+        dep.explicit_name_in_toml
+            .clone()
+            .unwrap_or_else(|| dep.name.clone()),
+        dep.kind.clone().unwrap_or_else(|| "normal".to_string()),
+        dep.target.clone(),
+    )
+}
+
+/// `1.0` and `^1.0` are the same requirement, cargo writes the caret in the metadata only
+fn same_req(a: &str, b: &str) -> bool {
+    let caret = |req: &str| {
+        req.split(',')
+            .map(|r| {
+                let r = r.trim();
+                if r.starts_with(|c: char| c.is_ascii_digit()) {
+                    format!("^{}", r)
+                } else {
+                    r.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let (a, b) = (caret(a), caret(b));
+    match (VersionReq::parse(&a), VersionReq::parse(&b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// whether a path, or the target of a link there, goes out of the `root` directory
+fn escapes(root: &Path, path: &Path) -> bool {
+    let mut normalized = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => normalized.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if normalized.pop().is_none() {
+                    return true;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+
+    normalized
+        .first()
+        .map_or(true, |first| Path::new(first) != root)
+}
+
+/// check the `Cargo.toml` in a crate file agrees with the metadata published
+fn check_manifest(info: &CrateInfo, manifest: &str) -> Vec<String> {
+    let manifest: Manifest = match toml::from_str(manifest) {
+        Ok(manifest) => manifest,
+        Err(e) => return vec![format!("invalid Cargo.toml in the crate file: {}", e)],
+    };
+
+    let mut errors = Vec::new();
+    if manifest.package.name != info.name {
+        errors.push(format!(
+            "the crate name is {} in Cargo.toml, but {} in the metadata",
+            manifest.package.name, info.name
+        ));
+    }
+
+    let same_version = match (
+        Version::parse(&manifest.package.version),
+        Version::parse(&info.vers),
+    ) {
+        (Ok(a), Ok(b)) => a == b && a.build == b.build,
+        _ => manifest.package.version == info.vers,
+    };
+    if !same_version {
+        errors.push(format!(
+            "the version is {} in Cargo.toml, but {} in the metadata",
+            manifest.package.version, info.vers
+        ));
+    }
+
+    let mut in_manifest = manifest.deps();
+    for dep in &info.deps {
+        let key = dep_key(dep);
+        match in_manifest.remove(&key) {
+            None => errors.push(format!(
+                "{} dependency {} is in the metadata, but not in Cargo.toml",
+                key.1, key.0
+            )),
+            Some((package, req)) => {
+                if package != dep.name || !same_req(&req, &dep.version_req) {
+                    errors.push(format!(
+                        "{} dependency {} is {} {} in Cargo.toml, but {} {} in the metadata",
+                        key.1, key.0, package, req, dep.name, dep.version_req
+                    ));
+                }
+            }
+        }
+    }
+    errors.extend(in_manifest.keys().map(|(name, kind, _)| {
+        format!(
+            "{} dependency {} is in Cargo.toml, but not in the metadata",
+            kind, name
+        )
+    }));
+
+    errors
+}
+
+fn read(info: &CrateInfo, data: &[u8]) -> Result<(Vec<CrateFile>, Vec<String>)> {
+    let root = format!("{}-{}", info.name, info.vers);
+    let root = Path::new(&root);
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    let mut manifest = None;
+    let mut unpacked = 0u64;
+
+    let mut archive = Archive::new(GzDecoder::new(data));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let display = path.to_string_lossy().to_string();
+        if escapes(root, &path) {
+            errors.push(format!(
+                "{} in the crate file is not in the {} directory",
+                display,
+                root.display()
+            ));
+            continue;
+        }
+
+        unpacked += entry.size();
+        if unpacked > MAX_UNPACKED {
+            bail!("the crate file unpacks to more than {} bytes", MAX_UNPACKED);
+        }
+
+        let entry_type = entry.header().entry_type();
+        match entry_type {
+            EntryType::Regular | EntryType::Continuous | EntryType::Directory => {}
+            EntryType::Symlink | EntryType::Link => {
+                let target = entry.link_name()?.map(|t| t.into_owned());
+                // the target of a symbolic link is relative to the link, a hard link to the root
+                let escaped = match &target {
+                    Some(t) if entry_type == EntryType::Symlink => {
+                        escapes(root, &path.parent().unwrap_or(root).join(t))
+                    }
+                    Some(t) => escapes(root, t),
+                    None => true,
+                };
+                if escaped {
+                    errors.push(format!(
+                        "the link {} in the crate file points out of the {} directory",
+                        display,
+                        root.display()
+                    ));
+                    continue;
+                }
+            }
+            other => {
+                errors.push(format!(
+                    "{} in the crate file is a {:?}, only files, directories and links are allowed",
+                    display, other
+                ));
+                continue;
+            }
+        }
+
+        if entry_type == EntryType::Directory {
+            continue;
+        }
+
+        if path == root.join("Cargo.toml") {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            manifest = Some(content);
+        }
+
+        files.push(CrateFile {
+            path: path
+                .strip_prefix(root)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .replace('\\', "/"),
+            size: entry.size() as i64,
+        });
+    }
+
+    match manifest {
+        Some(manifest) => errors.extend(check_manifest(info, &manifest)),
+        None => errors.push(format!(
+            "no {}/Cargo.toml in the crate file",
+            root.display()
+        )),
+    }
+
+    Ok((files, errors))
+}
+
+/// unpack a crate file in memory, check its paths and `Cargo.toml` against the metadata,
+/// return the files in it and the problems found
+pub(super) fn inspect(info: &CrateInfo, data: &[u8]) -> (Vec<CrateFile>, Vec<String>) {
+    match read(info, data) {
+        Ok(result) => result,
+        Err(e) => (
+            Vec::new(),
+            vec![format!("the crate file can not be unpacked: {}", e)],
+        ),
+    }
+}
@@ -0,0 +1,130 @@
+use super::unsecure_http_client;
+use crate::config::Config;
+use anyhow::{bail, Result};
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
+use reqwest::{header, Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Arc};
+use tokio::{fs, sync::RwLock};
+
+/// validators of the cached upstream index file, used for revalidation
+#[derive(Serialize, Deserialize, Default)]
+struct CacheMeta {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// upstream index fetched on demand over the sparse protocol
+pub(crate) struct UpstreamIndex {
+    config: Arc<RwLock<Config>>,
+    /// shared by all the fetches, so the connections to upstream are reused
+    client: OnceCell<Client>,
+}
+
+impl UpstreamIndex {
+    pub(super) fn new(config: Arc<RwLock<Config>>) -> Self {
+        UpstreamIndex {
+            config,
+            client: OnceCell::new(),
+        }
+    }
+
+    /// get the upstream index file at `relative_path`, eg. `se/rd/serde`.
+    /// return `None` if the lazy mirror mode is off or upstream does not have this crate.
+    /// the cached copy is revalidated with ETag/Last-Modified on every call,
+    /// and it's used as is when upstream is unreachable.
+    pub(super) async fn get(&self, relative_path: &str) -> Result<Option<Vec<u8>>> {
+        let (url, cache_path) = {
+            let cfg = self.config.read().await;
+            match &cfg.git.upstream_sparse_url {
+                Some(url) => (
+                    format!("{}/{}", url.trim_end_matches('/'), relative_path),
+                    cfg.git.sparse_cache_path.join(relative_path),
+                ),
+                None => return Ok(None),
+            }
+        };
+
+        let meta_path = cache_path.with_extension("meta");
+        let cached = fs::read(&cache_path).await.ok();
+        let mut request = self
+            .client
+            .get_or_try_init(unsecure_http_client)?
+            .get(&url)
+            .header("User-Agent", "mirror_registry (avalon1610@gmail.com)");
+        if cached.is_some() {
+            let meta: CacheMeta = fs::read(&meta_path)
+                .await
+                .ok()
+                .and_then(|m| serde_json::from_slice(&m).ok())
+                .unwrap_or_default();
+            if let Some(etag) = meta.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+
+            if let Some(last_modified) = meta.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = match request.send().await {
+            Ok(r) => r,
+            Err(e) if cached.is_some() => {
+                warn!("fetch {} failed, use the cached one: {:?}", url, e);
+                return Ok(cached);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match response.status() {
+            StatusCode::NOT_MODIFIED if cached.is_some() => {
+                debug!("{} not modified", url);
+                Ok(cached)
+            }
+            StatusCode::NOT_FOUND
+            | StatusCode::GONE
+            | StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS => {
+                let _ = fs::remove_file(&cache_path).await;
+                let _ = fs::remove_file(&meta_path).await;
+                Ok(None)
+            }
+            s if s.is_success() => {
+                let header_value = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|v: &header::HeaderValue| v.to_str().ok())
+                        .map(|v| v.to_string())
+                };
+                let meta = CacheMeta {
+                    etag: header_value(header::ETAG),
+                    last_modified: header_value(header::LAST_MODIFIED),
+                };
+                let content = response.bytes().await?.to_vec();
+                debug!("fetched {}, {} bytes", url, content.len());
+
+                save(&cache_path, &content).await?;
+                save(&meta_path, &serde_json::to_vec(&meta)?).await?;
+                Ok(Some(content))
+            }
+            s if cached.is_some() => {
+                warn!("fetch {} return {}, use the cached one", url, s);
+                Ok(cached)
+            }
+            s => bail!("fetch {} return {}", url, s),
+        }
+    }
+}
+
+/// write to a temp file then rename, readers never see a half written file
+async fn save(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+
+    let tmp = path.with_extension(format!("{:x}.tmp", rand::random::<u32>()));
+    fs::write(&tmp, content).await?;
+    fs::rename(tmp, path).await?;
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use super::models::CrateInfo;
+use crate::config::Config;
+use semver::Version;
+
+/// the names can not be used by any crate, compared case insensitively with `-` as `_`
+const RESERVED_NAMES: &[&str] = &[
+    "alloc",
+    "core",
+    "proc_macro",
+    "std",
+    "test",
+    "rust",
+    "rustc",
+    "cargo",
+    // reserved file names on windows
+    "nul",
+    "con",
+    "prn",
+    "aux",
+    "com1",
+    "com2",
+    "com3",
+    "com4",
+    "com5",
+    "com6",
+    "com7",
+    "com8",
+    "com9",
+    "lpt1",
+    "lpt2",
+    "lpt3",
+    "lpt4",
+    "lpt5",
+    "lpt6",
+    "lpt7",
+    "lpt8",
+    "lpt9",
+];
+const MAX_NAME_LEN: usize = 64;
+const MAX_KEYWORDS: usize = 5;
+const MAX_KEYWORD_LEN: usize = 20;
+const MAX_CATEGORIES: usize = 5;
+const MAX_CATEGORY_LEN: usize = 64;
+
+#[cfg(test)]
+mod test {
+    use super::{check_keyword, check_name, validate};
+    use crate::crates_io::models::CrateInfo;
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("serde_json").is_ok());
+        assert!(check_name("tokio-util").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("1password").is_err());
+        assert!(check_name("foo.bar").is_err());
+        assert!(check_name(&"a".repeat(65)).is_err());
+        assert!(check_name("Proc-Macro").is_err());
+        assert!(check_name("nul").is_err());
+    }
+
+    #[test]
+    fn test_check_keyword() {
+        assert!(check_keyword("no_std").is_ok());
+        assert!(check_keyword("c++").is_ok());
+        assert!(check_keyword("-cli").is_err());
+        assert!(check_keyword("web assembly").is_err());
+        assert!(check_keyword(&"k".repeat(21)).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let info: CrateInfo = serde_json::from_str(
+            r#"{
+                "name": "std", "vers": "1.0", "deps": [], "features": {}, "authors": [],
+                "description": null, "documentation": null, "homepage": null, "readme": null,
+                "readme_file": null, "keywords": ["a", "b", "c", "d", "e", "f"],
+                "categories": [], "license": null, "license_file": null, "repository": null,
+                "badges": {}, "links": null
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(validate(&info).len(), 3);
+    }
+}
+
+/// the size limits of a publish request, from `crates.max_metadata_kb` and `crates.max_crate_mb`
+pub(super) struct Limits {
+    pub metadata: usize,
+    pub crate_file: usize,
+}
+
+impl Limits {
+    pub(super) fn new(config: &Config) -> Self {
+        Limits {
+            metadata: config.crates.max_metadata_kb as usize * 1024,
+            crate_file: config.crates.max_crate_mb as usize * 1024 * 1024,
+        }
+    }
+
+    /// the largest request body, the two lengths take 4 bytes each
+    pub(super) fn body(&self) -> usize {
+        8 + self.metadata + self.crate_file
+    }
+}
+
+/// the same rules as crates.io: ascii letters, digits, `-` and `_`, starts with a letter
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("crate name can not be empty".to_string());
+    }
+
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "crate name {} is longer than {} characters",
+            name, MAX_NAME_LEN
+        ));
+    }
+
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(format!("crate name {} must start with a letter", name));
+    }
+
+    if let Some(c) = name
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && *c != '-' && *c != '_')
+    {
+        return Err(format!(
+            "invalid character `{}` in crate name {}, only letters, numbers, `-` and `_` are allowed",
+            c, name
+        ));
+    }
+
+    let normalized = name.to_lowercase().replace('-', "_");
+    if RESERVED_NAMES.contains(&normalized.as_str()) {
+        return Err(format!("crate name {} is reserved", name));
+    }
+
+    Ok(())
+}
+
+/// ascii letters, digits, `_`, `-` and `+`, starts with a letter or digit
+fn check_keyword(keyword: &str) -> Result<(), String> {
+    if keyword.is_empty() || keyword.len() > MAX_KEYWORD_LEN {
+        return Err(format!(
+            "keyword `{}` must be 1 to {} characters",
+            keyword, MAX_KEYWORD_LEN
+        ));
+    }
+
+    if !keyword.starts_with(|c: char| c.is_ascii_alphanumeric())
+        || !keyword
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+')
+    {
+        return Err(format!(
+            "invalid keyword `{}`, only letters, numbers, `_`, `-` and `+` are allowed, \
+             starts with a letter or number",
+            keyword
+        ));
+    }
+
+    Ok(())
+}
+
+/// all the problems of the metadata, empty if it is good to publish
+pub(super) fn validate(info: &CrateInfo) -> Vec<String> {
+    let mut errors = Vec::new();
+    if let Err(e) = check_name(&info.name) {
+        errors.push(e);
+    }
+
+    if let Err(e) = Version::parse(&info.vers) {
+        errors.push(format!("invalid version {}: {}", info.vers, e));
+    }
+
+    if info.keywords.len() > MAX_KEYWORDS {
+        errors.push(format!(
+            "expected at most {} keywords, got {}",
+            MAX_KEYWORDS,
+            info.keywords.len()
+        ));
+    }
+    errors.extend(info.keywords.iter().filter_map(|k| check_keyword(k).err()));
+
+    if info.categories.len() > MAX_CATEGORIES {
+        errors.push(format!(
+            "expected at most {} categories, got {}",
+            MAX_CATEGORIES,
+            info.categories.len()
+        ));
+    }
+    errors.extend(
+        info.categories
+            .iter()
+            .filter(|c| c.is_empty() || c.len() > MAX_CATEGORY_LEN)
+            .map(|c| {
+                format!(
+                    "category `{}` must be 1 to {} characters",
+                    c, MAX_CATEGORY_LEN
+                )
+            }),
+    );
+
+    errors
+}
@@ -0,0 +1,367 @@
+use super::{db, models::IndexMetadata, quarantine_to, relative_path, Quarantined};
+use crate::{auth::check, config::Config, database::Database, git::Git, storage::Storage, Server};
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spa_server::re_export::{
+    error::ErrorInternalServerError, post, web, HttpResponse, Identity, Responder,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    sync::{Mutex, RwLock},
+    task,
+};
+use walkdir::WalkDir;
+
+#[cfg(test)]
+mod test {
+    use super::crate_file_version;
+
+    #[test]
+    fn test_crate_file_version() {
+        assert_eq!(
+            crate_file_version("serde", "serde-1.0.100.crate"),
+            Some("1.0.100")
+        );
+        assert_eq!(
+            crate_file_version("tokio-util", "tokio-util-0.6.0-beta.1.crate"),
+            Some("0.6.0-beta.1")
+        );
+        assert_eq!(crate_file_version("serde", "serde_json-1.0.0.crate"), None);
+        assert_eq!(crate_file_version("serde", "serde-1.0.100.crate.tmp"), None);
+    }
+}
+
+/// a problem found by the verifier
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Problem {
+    /// a line of index file can not be parsed as `IndexMetadata`
+    InvalidIndexLine {
+        path: PathBuf,
+        line: usize,
+        error: String,
+    },
+    /// the stored crate file does not match the `cksum` in index
+    ChecksumMismatch {
+        key: String,
+        expected: String,
+        actual: String,
+    },
+    /// the stored crate file has no entry in index
+    UnknownCrateFile { key: String },
+    /// a version of the private crate in database has no entry in index
+    MissingIndexEntry { name: String, version: String },
+    /// the work tree and the bare repo are not on the same commit
+    CommitMismatch {
+        working: Option<String>,
+        bare: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+pub(crate) struct Finding {
+    #[serde(flatten)]
+    problem: Problem,
+    repaired: bool,
+}
+
+/// the machine-readable result of a verify job
+#[derive(Serialize, Default)]
+pub(crate) struct Report {
+    index_files: usize,
+    index_lines: usize,
+    crate_files: usize,
+    findings: Vec<Finding>,
+}
+
+impl Report {
+    /// whether there are problems not repaired
+    pub(crate) fn is_clean(&self) -> bool {
+        self.findings.iter().all(|f| f.repaired)
+    }
+
+    fn found(&mut self, problem: Problem, repaired: bool) {
+        warn!(
+            "verify found {}",
+            serde_json::to_string(&problem).unwrap_or_default()
+        );
+        self.findings.push(Finding { problem, repaired });
+    }
+}
+
+/// check the index, the crate storage, the database and the git repos agree with each other.
+/// with `repair`, the corrupted crate files are moved to `crates.quarantine_path` (the upstream
+/// ones are downloaded again on demand) and the bare repo is synced with the work tree.
+pub(crate) async fn verify(
+    config: &Arc<RwLock<Config>>,
+    git: &Git,
+    database: &Mutex<Database>,
+    storage: &dyn Storage,
+    repair: bool,
+) -> Result<Report> {
+    let (index_roots, quarantine_path) = {
+        let cfg = config.read().await;
+        let mut roots = vec![cfg.git.working_path.clone()];
+        if cfg.git.upstream_sparse_url.is_some() {
+            roots.push(cfg.git.sparse_cache_path.clone());
+        }
+
+        (roots, cfg.crates.quarantine_path.clone())
+    };
+
+    let roots = index_roots.clone();
+    let mut report = task::spawn_blocking(move || {
+        let mut report = Report::default();
+        for root in &roots {
+            check_index_files(root, &mut report);
+        }
+
+        report
+    })
+    .await?;
+    let quarantine_path = if repair {
+        Some(quarantine_path.as_path())
+    } else {
+        None
+    };
+    check_crate_files(&index_roots, storage, quarantine_path, &mut report).await?;
+
+    let working_path = config.read().await.git.working_path.clone();
+    let private_versions = db::private_versions(&*database.lock().await)?;
+    let mut indexed: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, version) in private_versions {
+        let found = indexed
+            .entry(name.clone())
+            .or_insert_with(|| {
+                index_entries(&[working_path.clone()], &name)
+                    .into_iter()
+                    .map(|m| m.vers)
+                    .collect()
+            })
+            .contains(&version);
+        if !found {
+            report.found(Problem::MissingIndexEntry { name, version }, false);
+        }
+    }
+
+    let (working, bare) = git.heads().await;
+    if working != bare {
+        let repaired = repair && git.sync_index().await.is_ok();
+        report.found(Problem::CommitMismatch { working, bare }, repaired);
+    }
+
+    info!(
+        "verify finished, {} index files, {} crate files, {} problems",
+        report.index_files,
+        report.crate_files,
+        report.findings.len()
+    );
+    Ok(report)
+}
+
+fn is_index_file(entry: &walkdir::DirEntry) -> bool {
+    let name = entry.file_name().to_string_lossy();
+    entry.file_type().is_file()
+        && entry.depth() > 1
+        && !name.ends_with(".meta")
+        && !name.ends_with(".tmp")
+}
+
+fn check_index_files(root: &Path, report: &mut Report) {
+    let walker = WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != ".git");
+    for entry in walker.filter_map(|e| e.ok()).filter(is_index_file) {
+        report.index_files += 1;
+        let content = match fs::read(entry.path()) {
+            Ok(c) => c,
+            Err(e) => {
+                report.found(
+                    Problem::InvalidIndexLine {
+                        path: entry.path().to_path_buf(),
+                        line: 0,
+                        error: format!("{:?}", e),
+                    },
+                    false,
+                );
+                continue;
+            }
+        };
+
+        for (i, line) in String::from_utf8_lossy(&content).lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            report.index_lines += 1;
+            if let Err(e) = serde_json::from_str::<IndexMetadata>(line.trim()) {
+                report.found(
+                    Problem::InvalidIndexLine {
+                        path: entry.path().to_path_buf(),
+                        line: i + 1,
+                        error: e.to_string(),
+                    },
+                    false,
+                );
+            }
+        }
+    }
+}
+
+/// the version of a stored crate file, eg. `serde-1.0.100.crate` of `serde` is `1.0.100`
+pub(super) fn crate_file_version<'a>(name: &str, file_name: &'a str) -> Option<&'a str> {
+    let version = file_name
+        .strip_prefix(name)?
+        .strip_prefix('-')?
+        .strip_suffix(".crate")?;
+    if version.starts_with(|c: char| c.is_ascii_digit()) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// the entries of crate `name` in the local index files, the unparsable lines are skipped
+fn index_entries(roots: &[PathBuf], name: &str) -> Vec<IndexMetadata> {
+    let mut paths = vec![relative_path(name)];
+    if name.to_lowercase() != name {
+        paths.push(relative_path(&name.to_lowercase()));
+    }
+
+    let mut entries = Vec::new();
+    for path in &paths {
+        for root in roots {
+            if let Ok(content) = fs::read(root.join(path)) {
+                entries.extend(
+                    String::from_utf8_lossy(&content)
+                        .lines()
+                        .filter_map(|l| serde_json::from_str::<IndexMetadata>(l.trim()).ok())
+                        .filter(|m| m.name == name),
+                );
+            }
+        }
+    }
+
+    entries
+}
+
+async fn check_crate_files(
+    index_roots: &[PathBuf],
+    storage: &dyn Storage,
+    quarantine_path: Option<&Path>,
+    report: &mut Report,
+) -> Result<()> {
+    let mut cksums = HashMap::new();
+    for object in storage.list().await? {
+        let mut parts = object.key.splitn(2, '/');
+        let (name, file_name) = match (parts.next(), parts.next()) {
+            (Some(n), Some(f)) => (n.to_string(), f),
+            _ => continue,
+        };
+        let version = match crate_file_version(&name, file_name) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        report.crate_files += 1;
+        let entries = cksums.entry(name.clone()).or_insert_with(|| {
+            index_entries(index_roots, &name)
+                .into_iter()
+                .map(|m| (m.vers, m.cksum))
+                .collect::<HashMap<_, _>>()
+        });
+        let expected = match entries.get(version) {
+            Some(c) => c.clone(),
+            None => {
+                report.found(Problem::UnknownCrateFile { key: object.key }, false);
+                continue;
+            }
+        };
+
+        let data = match storage.get(&object.key).await? {
+            Some(d) => d,
+            None => continue,
+        };
+        let actual = format!("{:x}", Sha256::digest(&data));
+        if actual != expected {
+            let repaired = match quarantine_path {
+                Some(dir) => {
+                    move_aside(storage, dir, &object.key, (&name, version, &actual), &data).await
+                }
+                None => false,
+            };
+            report.found(
+                Problem::ChecksumMismatch {
+                    key: object.key,
+                    expected,
+                    actual,
+                },
+                repaired,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// copy a corrupted crate file into the quarantine directory, then delete it from storage
+async fn move_aside(
+    storage: &dyn Storage,
+    dir: &Path,
+    key: &str,
+    (name, version, checksum): (&str, &str, &str),
+    data: &[u8],
+) -> bool {
+    let result = async {
+        let path = quarantine_to(dir, name, version, checksum, Quarantined::Bytes(data)).await?;
+        storage.delete(key).await?;
+        Ok::<_, anyhow::Error>(path)
+    }
+    .await;
+    match result {
+        Ok(path) => {
+            warn!("{} is corrupted, moved to {:?}", key, path);
+            true
+        }
+        Err(e) => {
+            warn!("move {} aside failed: {:?}", key, e);
+            false
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct VerifyParam {
+    #[serde(default)]
+    repair: bool,
+}
+
+/// run the verify job, eg. `POST /web_api/verify?repair=true`
+#[post("verify")]
+pub(crate) async fn verify_storage(
+    param: web::Query<VerifyParam>,
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    if !check(&id, &*data.database.lock().await)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let report = verify(
+        &data.config,
+        &data.git,
+        &data.database,
+        &*data.storage,
+        param.repair,
+    )
+    .await
+    .map_err(|e| ErrorInternalServerError(format!("verify failed: {:?}", e)))?;
+    Ok(HttpResponse::Ok().json(report))
+}
@@ -0,0 +1,204 @@
+use super::models::CrateInfo;
+use crate::{
+    auth::check,
+    database::{schema::allowed_metadata, Database},
+    Server,
+};
+use anyhow::Result;
+use chrono::Local;
+use diesel::prelude::*;
+use log::info;
+use serde::Serialize;
+use spa_server::re_export::{
+    delete,
+    error::{ErrorBadRequest, ErrorInternalServerError},
+    get, put, web, HttpResponse, Identity, Responder,
+};
+
+const CATEGORIES: &str = "categories";
+const BADGES: &str = "badges";
+
+#[cfg(test)]
+mod test {
+    use super::warnings;
+    use crate::crates_io::models::CrateInfo;
+
+    #[test]
+    fn test_warnings() {
+        let info: CrateInfo = serde_json::from_str(
+            r#"{
+                "name": "foo", "vers": "1.0.0", "deps": [], "features": {}, "authors": [],
+                "description": "", "documentation": null, "homepage": null, "readme": null,
+                "readme_file": null, "keywords": [], "categories": ["no-std", "cooking"],
+                "license": null, "license_file": "LICENSE", "repository": null,
+                "badges": {"travis-ci": {"repository": "foo/bar"}, "jenkins": {}},
+                "links": null
+            }"#,
+        )
+        .unwrap();
+
+        let w = warnings(&info, &["no-std".to_string()], &["travis-ci".to_string()]);
+        assert_eq!(w.invalid_categories, vec!["cooking"]);
+        assert_eq!(w.invalid_badges, vec!["jenkins"]);
+        assert_eq!(w.other.len(), 1);
+
+        // nothing is checked against an empty list
+        let w = warnings(&info, &[], &[]);
+        assert!(w.invalid_categories.is_empty() && w.invalid_badges.is_empty());
+    }
+}
+
+/// the `warnings` of a publish response, cargo prints them after publishing
+#[derive(Serialize, Default)]
+pub(super) struct Warnings {
+    invalid_categories: Vec<String>,
+    invalid_badges: Vec<String>,
+    other: Vec<String>,
+}
+
+/// a category or a badge type allowed by admin
+#[derive(Queryable, Insertable, Serialize)]
+#[table_name = "allowed_metadata"]
+struct AllowedMetadata {
+    #[serde(skip)]
+    kind: String,
+    value: String,
+    allowed_by: String,
+    created_at: String,
+}
+
+/// the categories and badges not in the allowed lists, an empty list allows anything
+fn warnings(info: &CrateInfo, categories: &[String], badges: &[String]) -> Warnings {
+    let unknown =
+        |allowed: &[String], value: &String| !allowed.is_empty() && !allowed.contains(value);
+
+    let mut invalid_badges = info
+        .badges
+        .keys()
+        .filter(|b| unknown(badges, b))
+        .cloned()
+        .collect::<Vec<_>>();
+    invalid_badges.sort();
+
+    let mut other = Vec::new();
+    if info
+        .description
+        .as_deref()
+        .map_or(true, |d| d.trim().is_empty())
+    {
+        other.push("no description in the manifest".to_string());
+    }
+    if info.license.is_none() && info.license_file.is_none() {
+        other.push("no license or license-file in the manifest".to_string());
+    }
+
+    Warnings {
+        invalid_categories: info
+            .categories
+            .iter()
+            .filter(|c| unknown(categories, c))
+            .cloned()
+            .collect(),
+        invalid_badges,
+        other,
+    }
+}
+
+fn allowed(db: &Database, kind: &str) -> Result<Vec<String>> {
+    Ok(allowed_metadata::table
+        .filter(allowed_metadata::kind.eq(kind))
+        .select(allowed_metadata::value)
+        .load::<String>(&db.connection)?)
+}
+
+/// check a crate to publish against the allowed categories and badges
+pub(super) fn check_metadata(db: &Database, info: &CrateInfo) -> Result<Warnings> {
+    Ok(warnings(
+        info,
+        &allowed(db, CATEGORIES)?,
+        &allowed(db, BADGES)?,
+    ))
+}
+
+fn check_kind(kind: &str) -> spa_server::re_export::Result<()> {
+    if kind != CATEGORIES && kind != BADGES {
+        return Err(ErrorBadRequest(format!(
+            "unknown kind {}, only {} and {}",
+            kind, CATEGORIES, BADGES
+        )));
+    }
+
+    Ok(())
+}
+
+/// the allowed categories or badge types, `kind` is `categories` or `badges`
+#[get("allowed/{kind}")]
+pub(crate) async fn list_allowed(
+    info: web::Path<String>,
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    let db = data.database.lock().await;
+    if !check(&id, &db)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let kind = info.into_inner();
+    check_kind(&kind)?;
+    let allowed = allowed_metadata::table
+        .filter(allowed_metadata::kind.eq(&kind))
+        .order(allowed_metadata::value.asc())
+        .load::<AllowedMetadata>(&db.connection)
+        .map_err(|e| ErrorInternalServerError(format!("load allowed {} failed: {:?}", kind, e)))?;
+    Ok(HttpResponse::Ok().json(allowed))
+}
+
+#[put("allowed/{kind}/{value}")]
+pub(crate) async fn allow_metadata(
+    info: web::Path<(String, String)>,
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    let db = data.database.lock().await;
+    let account = check(&id, &db)?;
+    if !account.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let (kind, value) = info.into_inner();
+    check_kind(&kind)?;
+    diesel::replace_into(allowed_metadata::table)
+        .values(AllowedMetadata {
+            kind: kind.clone(),
+            value: value.clone(),
+            allowed_by: account.username.clone(),
+            created_at: Local::now().to_string(),
+        })
+        .execute(&db.connection)
+        .map_err(|e| ErrorInternalServerError(format!("allow {} failed: {:?}", value, e)))?;
+
+    info!("{} allowed {} {}", account.username, kind, value);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("allowed/{kind}/{value}")]
+pub(crate) async fn disallow_metadata(
+    info: web::Path<(String, String)>,
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    let db = data.database.lock().await;
+    let account = check(&id, &db)?;
+    if !account.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let (kind, value) = info.into_inner();
+    check_kind(&kind)?;
+    diesel::delete(allowed_metadata::table.find((&kind, &value)))
+        .execute(&db.connection)
+        .map_err(|e| ErrorInternalServerError(format!("disallow {} failed: {:?}", value, e)))?;
+
+    info!("{} disallowed {} {}", account.username, kind, value);
+    Ok(HttpResponse::Ok().finish())
+}
@@ -31,11 +31,32 @@ impl Database {
         Ok(Database { connection })
     }
 
+    /// a database in memory with the migrations run
+    #[cfg(test)]
+    pub(crate) fn memory() -> Self {
+        let connection = SqliteConnection::establish(":memory:").unwrap();
+        embedded_migrations::run(&connection).unwrap();
+        Database { connection }
+    }
+
+    /// another connection to the database set up by `new`
+    pub async fn connect(config: Arc<RwLock<Config>>) -> Result<Self> {
+        let connection = Database::establish_connection(config).await?;
+        Ok(Database { connection })
+    }
+
+    /// every connection waits for the others writing instead of failing with `SQLITE_BUSY`,
+    /// and the readers do not block the writer in the WAL mode
     async fn establish_connection(config: Arc<RwLock<Config>>) -> Result<SqliteConnection> {
-        Ok(
-            SqliteConnection::establish(&config.read().await.database.url)
-                .context("connect to database failed")?,
-        )
+        let connection = SqliteConnection::establish(&config.read().await.database.url)
+            .context("connect to database failed")?;
+        connection
+            .execute("PRAGMA busy_timeout = 5000")
+            .context("set busy timeout failed")?;
+        connection
+            .execute("PRAGMA journal_mode = WAL")
+            .context("set journal mode failed")?;
+        Ok(connection)
     }
 }
 
@@ -15,6 +15,74 @@ table! {
     }
 }
 
+table! {
+    allowed_metadata (kind, value) {
+        kind -> Text,
+        value -> Text,
+        allowed_by -> Text,
+        created_at -> Text,
+    }
+}
+
+table! {
+    bundle_exports (id) {
+        id -> Integer,
+        commit -> Text,
+        crates -> Text,
+        created_at -> Text,
+    }
+}
+
+table! {
+    crate_blobs (key) {
+        key -> Text,
+        cksum -> Text,
+        size -> BigInt,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    crate_cache (key) {
+        key -> Text,
+        size -> BigInt,
+        last_access -> Timestamp,
+    }
+}
+
+table! {
+    crate_files (name, version, path) {
+        name -> Text,
+        version -> Text,
+        path -> Text,
+        size -> BigInt,
+    }
+}
+
+table! {
+    crate_versions (name, version) {
+        name -> Text,
+        version -> Text,
+        description -> Nullable<Text>,
+        license -> Nullable<Text>,
+        license_file -> Nullable<Text>,
+        authors -> Text,
+        homepage -> Nullable<Text>,
+        documentation -> Nullable<Text>,
+        repository -> Nullable<Text>,
+        readme -> Nullable<Text>,
+        readme_file -> Nullable<Text>,
+        keywords -> Text,
+        categories -> Text,
+        features -> Text,
+        deps -> Text,
+        links -> Nullable<Text>,
+        crate_size -> BigInt,
+        published_by -> Text,
+        created_at -> Text,
+    }
+}
+
 table! {
     crates (id) {
         id -> Text,
@@ -37,7 +105,47 @@ table! {
     }
 }
 
+table! {
+    shadow_overrides (name) {
+        name -> Text,
+        allowed_by -> Text,
+        created_at -> Text,
+    }
+}
+
+table! {
+    sync_history (id) {
+        id -> Integer,
+        source -> Text,
+        started_at -> Text,
+        finished_at -> Nullable<Text>,
+        commit_before -> Nullable<Text>,
+        commit_after -> Nullable<Text>,
+        crates_changed -> Nullable<Integer>,
+        error -> Nullable<Text>,
+        shadowed -> Nullable<Text>,
+    }
+}
+
+table! {
+    version_downloads (name, version, date) {
+        name -> Text,
+        version -> Text,
+        date -> Date,
+        downloads -> Integer,
+    }
+}
+
 allow_tables_to_appear_in_same_query!(
     accounts,
+    allowed_metadata,
+    bundle_exports,
+    crate_blobs,
+    crate_cache,
+    crate_files,
+    crate_versions,
     crates,
+    shadow_overrides,
+    sync_history,
+    version_downloads,
 );
@@ -0,0 +1,210 @@
+use super::{Git, GitCmd};
+use crate::crates_io::relative_path;
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use serde_json::Value;
+use std::{collections::HashSet, fs, path::Path};
+
+#[cfg(test)]
+mod test {
+    use super::merge_lines;
+
+    #[test]
+    fn test_merge_lines() {
+        let ours = "{\"name\":\"foo\",\"vers\":\"1.0.0\",\"cksum\":\"a\"}\n\
+                    {\"name\":\"foo\",\"vers\":\"1.1.0\",\"cksum\":\"b\"}\n";
+        let theirs = "{\"name\":\"foo\",\"vers\":\"1.0.0\",\"cksum\":\"a\"}\n\
+                      {\"name\":\"foo\",\"vers\":\"1.1.0\",\"cksum\":\"c\"}\n\
+                      {\"name\":\"foo\",\"vers\":\"2.0.0\",\"cksum\":\"d\"}\n";
+        let (merged, dropped) = merge_lines(ours, theirs);
+        assert_eq!(
+            merged,
+            "{\"name\":\"foo\",\"vers\":\"1.0.0\",\"cksum\":\"a\"}\n\
+             {\"name\":\"foo\",\"vers\":\"1.1.0\",\"cksum\":\"b\"}\n\
+             {\"name\":\"foo\",\"vers\":\"2.0.0\",\"cksum\":\"d\"}\n"
+        );
+        assert_eq!(dropped, vec!["foo-1.1.0".to_string()]);
+    }
+}
+
+/// where an index bundle is fetched to before it is merged
+const IMPORT_REF: &str = "refs/bundles/import";
+
+impl Git {
+    /// the `master` commit of the bare index repo
+    pub(crate) async fn index_head(&self) -> Result<String> {
+        GitCmd::dir(&self.config.read().await.git.index_path)
+            .run("rev-parse --verify master")
+            .context("the index has no commit yet")
+    }
+
+    /// bundle `master` of the bare index repo to `path`, only the commits after `base` if given,
+    /// return the bundled commit
+    pub(crate) async fn create_bundle(&self, path: &Path, base: Option<&str>) -> Result<String> {
+        let index_path = self.config.read().await.git.index_path.clone();
+        let revs = match base {
+            Some(base) => format!("{}..master", base),
+            None => "master".to_string(),
+        };
+        GitCmd::dir(&index_path)
+            .run(format!("bundle create {} {}", path.to_string_lossy(), revs))
+            .context("create index bundle failed")?;
+
+        GitCmd::dir(&index_path)
+            .run(format!("bundle list-heads {}", path.to_string_lossy()))?
+            .split_whitespace()
+            .next()
+            .map(|c| c.to_string())
+            .ok_or(anyhow!("no commit in the index bundle"))
+    }
+
+    /// fetch an index bundle into `IMPORT_REF`, so it can be read before it is merged
+    pub(crate) async fn fetch_bundle(&self, path: &Path) -> Result<()> {
+        self.init_repo().await?;
+        let cfg = self.config.read().await;
+        let git = GitCmd::dir(&cfg.git.working_path);
+        git.run(format!("bundle verify {}", path.to_string_lossy()))
+            .context("the bundle is based on the commits not imported yet")?;
+        git.run(format!(
+            "fetch --no-tags {} +master:{}",
+            path.to_string_lossy(),
+            IMPORT_REF
+        ))
+        .context("fetch index bundle failed")?;
+
+        Ok(())
+    }
+
+    /// the index file of a crate in the fetched bundle
+    pub(crate) async fn bundled_index_file(&self, name: &str) -> Option<String> {
+        GitCmd::dir(&self.config.read().await.git.working_path)
+            .run(format!(
+                "show {}:{}",
+                IMPORT_REF,
+                relative_path(&name.to_lowercase())
+            ))
+            .ok()
+    }
+
+    /// merge the fetched bundle as the upstream, in place of pulling the upstream, the index
+    /// files of the `kept` crates stay as they are here. it's queued and recorded in the sync
+    /// history like the other syncs. return the bundled lines dropped, as `name-version`
+    pub(crate) async fn import_bundle(&self, kept: Vec<String>) -> Result<Vec<String>> {
+        self.request_import(kept).await
+    }
+
+    pub(super) async fn merge_bundle(&self, kept: &[String]) -> Result<Vec<String>> {
+        let dropped = {
+            let cfg = self.config.read().await;
+            let git = GitCmd::dir(&cfg.git.working_path);
+            git.run(format!(
+                "update-ref refs/remotes/upstream/master {}",
+                IMPORT_REF
+            ))?;
+            let merged = git.run("merge --no-ff --no-commit upstream/master");
+            if git.run("rev-parse --verify --quiet MERGE_HEAD").is_err() {
+                // up to date, or the merge did not start
+                merged.context("merge index bundle failed")?;
+                Vec::new()
+            } else {
+                // the config.json of the exporter is not for us
+                let mut paths = vec!["config.json".to_string()];
+                paths.extend(kept.iter().map(|name| relative_path(&name.to_lowercase())));
+                let result = keep_ours(&git, &paths)
+                    .and_then(|_| merge_conflicts(&git, &cfg.git.working_path))
+                    .and_then(|dropped| git.run("commit --no-edit").map(|_| dropped));
+                match result {
+                    Ok(dropped) => dropped,
+                    Err(e) => {
+                        let _ = git.run("merge --abort");
+                        return Err(e.context("merge index bundle failed"));
+                    }
+                }
+            }
+        };
+        for line in &dropped {
+            warn!("{} in the bundle differs from the one here, dropped", line);
+        }
+
+        self.modify_config_json().await?;
+        Ok(dropped)
+    }
+}
+
+/// restore `paths` in a merge to the ones before it
+fn keep_ours(git: &GitCmd, paths: &[String]) -> Result<()> {
+    for path in paths {
+        if git.run(format!("cat-file -e HEAD:{}", path)).is_ok() {
+            git.run(format!("checkout HEAD -- {}", path))?;
+        } else {
+            git.run(format!("rm -f --ignore-unmatch -- {}", path))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// merge the conflicting index files in a merge line by line, return the lines dropped
+fn merge_conflicts(git: &GitCmd, working_path: &Path) -> Result<Vec<String>> {
+    let mut dropped = Vec::new();
+    for path in git
+        .run("diff --name-only --diff-filter=U")?
+        .lines()
+        .filter(|p| !p.is_empty())
+    {
+        // a side deleted the file has no stage of it
+        let ours = git.run(format!("show :2:{}", path)).unwrap_or_default();
+        let theirs = git.run(format!("show :3:{}", path)).unwrap_or_default();
+        let (merged, lines) = merge_lines(&ours, &theirs);
+        fs::write(working_path.join(path), merged)?;
+        git.run(format!("add -- {}", path))?;
+        dropped.extend(lines);
+    }
+
+    Ok(dropped)
+}
+
+/// merge two index files, the lines here are kept and the versions only in the bundle are added.
+/// a version in both but different is kept as it is here, and the bundled one returned as
+/// `name-version`
+fn merge_lines(ours: &str, theirs: &str) -> (String, Vec<String>) {
+    let key = |line: &str| {
+        serde_json::from_str::<Value>(line).ok().and_then(|v| {
+            Some((
+                v["name"].as_str()?.to_string(),
+                v["vers"].as_str()?.to_string(),
+            ))
+        })
+    };
+    let ours = ours
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .collect::<Vec<_>>();
+    let versions = ours
+        .iter()
+        .filter_map(|l| key(l))
+        .map(|(_, vers)| vers)
+        .collect::<HashSet<_>>();
+
+    let mut merged = ours.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+    let mut dropped = Vec::new();
+    for line in theirs.lines().filter(|l| !l.trim().is_empty()) {
+        if ours.contains(&line) {
+            continue;
+        }
+
+        match key(line) {
+            Some((name, vers)) if versions.contains(&vers) => {
+                dropped.push(format!("{}-{}", name, vers))
+            }
+            _ => merged.push(line.to_string()),
+        }
+    }
+
+    let mut merged = merged.join("\n");
+    if !merged.is_empty() {
+        merged.push('\n');
+    }
+
+    (merged, dropped)
+}
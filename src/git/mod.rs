@@ -0,0 +1,689 @@
+mod bundle;
+mod sync;
+mod upload_pack;
+
+use crate::{
+    auth::check_registry_token, config::Config, crates_io::UpstreamIndex, database::Database,
+    Server,
+};
+use anyhow::{anyhow, Context, Result};
+use futures::{channel::mpsc, executor, future, stream, SinkExt, StreamExt};
+use log::{debug, error, info};
+use serde::Serialize;
+use spa_server::re_export::{
+    error::{
+        ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound,
+        ErrorPayloadTooLarge,
+    },
+    get,
+    http::StatusCode,
+    post, web, HttpMessage, HttpRequest, HttpResponse,
+};
+use std::{
+    fs::{self, create_dir_all},
+    io::{self, BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    process,
+    sync::{Mutex, Notify, RwLock},
+    time,
+};
+
+pub(crate) use sync::{sync_now, sync_status, sync_webhook};
+use sync::{QueuedImport, SyncProgress, SyncSource};
+
+pub struct Git {
+    config: Arc<RwLock<Config>>,
+    database: Arc<Mutex<Database>>,
+    /// the one of `Index`, to check the shadowed crates in lazy mirror mode
+    upstream: Arc<UpstreamIndex>,
+    progress: std::sync::Mutex<Option<SyncProgress>>,
+    queued: Mutex<Option<SyncSource>>,
+    imports: Mutex<Vec<QueuedImport>>,
+    wakeup: Notify,
+
+    #[doc(hidden)]
+    pub inited: Mutex<bool>,
+
+    #[doc(hidden)]
+    pub busy: Mutex<bool>,
+}
+
+struct GitCmd<'a> {
+    cmd: &'static str,
+    dir: Option<&'a Path>,
+}
+#[cfg(test)]
+mod test {
+    use super::convert_args;
+
+    #[test]
+    fn test_convert_args() {
+        let test_str = "commit -m \"change config.json to mirror\"";
+        let result = vec!["commit", "-m", "\"change config.json to mirror\""];
+        assert_eq!(result, convert_args(test_str).unwrap())
+    }
+}
+
+fn convert_args(args: &str) -> anyhow::Result<Vec<String>> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let mut args_fixed = Vec::new();
+    let mut quote_args = String::new();
+    let mut in_quote = false;
+    for index in 0..args.len() {
+        if args[index].starts_with('"') {
+            quote_args = args[index].to_string();
+            in_quote = true;
+            continue;
+        }
+
+        if in_quote {
+            quote_args.push(' ');
+            quote_args.push_str(args[index]);
+
+            if args[index].ends_with('"') {
+                args_fixed.push(quote_args.clone());
+                in_quote = false;
+            }
+        } else {
+            args_fixed.push(args[index].to_string());
+        }
+    }
+
+    if in_quote {
+        return Err(anyhow!("found single quote args, abort"));
+    }
+
+    Ok(args_fixed)
+}
+
+impl<'a> GitCmd<'a> {
+    fn dir<'b: 'a>(dir: &'b Path) -> Self {
+        GitCmd {
+            cmd: "git",
+            dir: Some(dir),
+        }
+    }
+
+    #[allow(dead_code)]
+    fn new() -> Self {
+        GitCmd {
+            cmd: "git",
+            dir: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    fn cmd(cmd: &'static str) -> Self {
+        GitCmd { cmd, dir: None }
+    }
+
+    fn run(&self, args: impl AsRef<str>) -> anyhow::Result<String> {
+        debug!("command input : git {}", args.as_ref());
+
+        let mut cmd = Command::new(self.cmd);
+        if let Some(dir) = self.dir {
+            cmd.current_dir(dir);
+        }
+
+        let args = convert_args(args.as_ref())?;
+        debug!("args: {:?}", args);
+        let output = cmd
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?
+            .wait_with_output()
+            .context("run git command failed")?;
+
+        if output.status.success() {
+            let o = String::from_utf8_lossy(&*output.stdout);
+            let o = o.trim();
+            debug!("command output: {}", o);
+            Ok(o.to_string())
+        } else {
+            Err(
+                anyhow!("{}", String::from_utf8_lossy(&*output.stderr).to_string())
+                    .context("git command error"),
+            )
+        }
+    }
+
+    /// like `run`, but every progress line of stderr is fed to `on_progress`,
+    /// git ends the progress lines with `\r` and the others with `\n`
+    fn run_with_progress(
+        &self,
+        args: impl AsRef<str>,
+        mut on_progress: impl FnMut(&str),
+    ) -> anyhow::Result<()> {
+        debug!("command input : git {}", args.as_ref());
+
+        let mut cmd = Command::new(self.cmd);
+        if let Some(dir) = self.dir {
+            cmd.current_dir(dir);
+        }
+
+        let mut child = cmd
+            .args(convert_args(args.as_ref())?)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or(anyhow!("can not get stderr of git"))?;
+
+        let mut line = Vec::new();
+        let mut messages = Vec::new();
+        for b in BufReader::new(stderr).bytes() {
+            let b = b?;
+            if b != b'\r' && b != b'\n' {
+                line.push(b);
+                continue;
+            }
+
+            if !line.is_empty() {
+                let l = String::from_utf8_lossy(&line).to_string();
+                on_progress(&l);
+                if b == b'\n' {
+                    messages.push(l);
+                }
+                line.clear();
+            }
+        }
+
+        if child.wait().context("run git command failed")?.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("{}", messages.join("\n")).context("git command error"))
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct IndexConfig {
+    dl: String,
+    api: String,
+    #[serde(rename = "auth-required", skip_serializing_if = "is_false")]
+    auth_required: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl IndexConfig {
+    /// the config.json content served by both the git index and the sparse index
+    pub(crate) fn new(cfg: &Config) -> Self {
+        IndexConfig {
+            dl: format!("{}/api/v1/crates", cfg.registry.address),
+            api: cfg.registry.address.clone(),
+            auth_required: cfg.registry.auth_required,
+        }
+    }
+}
+
+impl Git {
+    pub async fn new(
+        cfg: Arc<RwLock<Config>>,
+        database: Arc<Mutex<Database>>,
+        upstream: Arc<UpstreamIndex>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let git = Arc::new(Git {
+            config: cfg.clone(),
+            database,
+            upstream,
+            progress: std::sync::Mutex::new(None),
+            queued: Mutex::new(None),
+            imports: Mutex::new(Vec::new()),
+            wakeup: Notify::new(),
+            inited: Mutex::new(false),
+            busy: Mutex::new(false),
+        });
+
+        let git_clone = git.clone();
+        let _ = tokio::spawn(async move {
+            let mut interval = cfg.read().await.registry.interval;
+            debug!("schedule start running, next: {}s", interval.as_secs());
+            loop {
+                time::sleep(interval).await;
+                interval = cfg.read().await.registry.interval;
+
+                if *git_clone.inited.lock().await {
+                    info!(
+                        "sync upstream by schedule now, next: {}s",
+                        interval.as_secs()
+                    );
+                    git_clone.request_sync(SyncSource::Schedule).await;
+                }
+            }
+        });
+
+        let worker = git.clone();
+        let _ = tokio::spawn(async move { worker.sync_worker().await });
+
+        Ok(git)
+    }
+
+    pub async fn initialize(&self) -> Result<()> {
+        self.init_repo().await?;
+        self.sync(SyncSource::Init).await?;
+        self.modify_config_json().await?;
+        self.sync_index().await?;
+
+        Ok(())
+    }
+
+    pub async fn sync_index(&self) -> Result<()> {
+        GitCmd::dir(&self.config.read().await.git.working_path)
+            .run("push origin master")
+            .context("sync with index failed")?;
+        Ok(())
+    }
+
+    pub async fn commit(&self, message: impl AsRef<str>) -> anyhow::Result<()> {
+        let working_path = &self.config.read().await.git.working_path;
+        GitCmd::dir(working_path).run("add .")?;
+        GitCmd::dir(working_path).run(format!("commit -m \"{}\"", message.as_ref()))?;
+        Ok(())
+    }
+
+    pub async fn modify_config_json(&self) -> Result<()> {
+        let cfg = self.config.read().await;
+        let config_json_path = cfg.git.working_path.join("config.json");
+        // there is no config.json in an empty index, eg. the lazy mirror mode
+        let content = if config_json_path.exists() {
+            fs::read_to_string(config_json_path).context("can not read config.json content")?
+        } else {
+            String::new()
+        };
+
+        let config_json = serde_json::to_string_pretty(&IndexConfig::new(&cfg))
+            .context("generate config.json failed")?;
+        if content != config_json {
+            fs::write(cfg.git.working_path.join("config.json"), config_json)
+                .context("write config.json failed")?;
+            GitCmd::dir(&cfg.git.working_path)
+                .run("add .")
+                .context("modify config json, run git add . failed")?;
+            GitCmd::dir(&cfg.git.working_path)
+                .run("commit -m \"change config.json to mirror\"")
+                .context("modify config json, run git commit failed")?;
+        }
+
+        Ok(())
+    }
+
+    async fn sync_upstream(&self) -> anyhow::Result<()> {
+        let cfg = self.config.read().await;
+        if cfg.git.upstream_sparse_url.is_some() {
+            debug!("lazy mirror mode, upstream index is fetched on demand");
+            return Ok(());
+        }
+
+        if cfg.registry.offline {
+            debug!("offline mode, upstream index is imported from bundles");
+            return Ok(());
+        }
+
+        // here --progress flag must be set
+        GitCmd::dir(&cfg.git.working_path)
+            .run_with_progress("pull --progress --no-stat upstream master", |l| {
+                self.update_progress(l)
+            })
+            .context("sync with upstream failed")?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn init_repo(&self) -> Result<()> {
+        check_git()?;
+        let cfg = self.config.read().await;
+        let mut bare_repo_inited = false;
+        let mut work_tree_inited = false;
+        if !Path::new(&cfg.git.index_path).exists() {
+            create_dir_all(&cfg.git.index_path)?;
+        }
+
+        if let Ok(r) = GitCmd::dir(&cfg.git.index_path).run("rev-parse --is-bare-repository") {
+            if r == "true" {
+                bare_repo_inited = true;
+                debug!("{:?} is a bare repo already", cfg.git.index_path);
+            }
+        }
+
+        if !bare_repo_inited {
+            info!("git init --bare for {:?}", cfg.git.index_path);
+
+            if let Err(e) = GitCmd::dir(&cfg.git.index_path).run("init --bare") {
+                return Err(e.context("git init --bare failed"));
+            }
+        }
+
+        if !Path::new(&cfg.git.working_path).exists() {
+            create_dir_all(&cfg.git.working_path)?;
+        }
+
+        if let Ok(r) = GitCmd::dir(&cfg.git.working_path).run("rev-parse --is-inside-work-tree") {
+            if r == "true" {
+                debug!("{:?} is a work tree already", cfg.git.working_path);
+                work_tree_inited = true;
+            }
+        }
+
+        if !work_tree_inited {
+            info!("git clone for {:?}", cfg.git.working_path);
+
+            if let Err(e) = GitCmd::dir(&cfg.git.working_path.parent().ok_or(anyhow!(
+                "work tree {:?} has no parent",
+                cfg.git.working_path
+            ))?)
+            .run(format!(
+                "clone {} {}",
+                cfg.git.index_path.to_string_lossy().to_string(),
+                cfg.git
+                    .working_path
+                    .file_name()
+                    .ok_or(anyhow!("work tree {:?} has no filename"))?
+                    .to_string_lossy()
+                    .to_string()
+            )) {
+                return Err(e.context("git clone failed"));
+            }
+
+            info!("git remote add upstream {}", cfg.git.upstream_url);
+
+            if let Err(e) = GitCmd::dir(&cfg.git.working_path)
+                .run(format!("remote add upstream {}", cfg.git.upstream_url))
+            {
+                return Err(e.context("git add remote failed"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// the sync and the index commits run git, the index is served without it
+fn check_git() -> Result<()> {
+    if let Err(e) = Command::new("git").stdout(Stdio::null()).spawn() {
+        if ErrorKind::NotFound == e.kind() {
+            return Err(anyhow!("git not found, you need install git first"));
+        }
+    }
+
+    Ok(())
+}
+
+#[post("/crates.io-index/.*")]
+pub(crate) async fn http_backend_post(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<Server>,
+) -> spa_server::re_export::Result<HttpResponse> {
+    http_backend(req, Some(body), data).await
+}
+
+#[get("/crates.io-index/.*")]
+pub(crate) async fn http_backend_get(
+    req: HttpRequest,
+    data: web::Data<Server>,
+) -> spa_server::re_export::Result<HttpResponse> {
+    http_backend(req, None, data).await
+}
+
+async fn http_backend(
+    req: HttpRequest,
+    body: Option<web::Payload>,
+    data: web::Data<Server>,
+) -> spa_server::re_export::Result<HttpResponse> {
+    if !*data.git.inited.lock().await {
+        return Err(ErrorBadRequest("System not initialized"));
+    }
+
+    let user = check_registry_token(&req, &data).await?;
+
+    debug!("git req:{:?}", req);
+    let mut path_info = req
+        .uri()
+        .to_string()
+        .replace("/registry/crates.io-index", "");
+    if let Some(i) = path_info.find('?') {
+        path_info = path_info.split_at(i).0.to_string();
+    }
+
+    let cgi = data.config.read().await.git.http_backend.clone();
+    match cgi {
+        Some(cgi) => cgi_backend(cgi, path_info, user, req, body, data).await,
+        None => builtin_backend(path_info, req, body, data).await,
+    }
+}
+
+/// a `Write` sending the written data to the response body, blocks when the client is slow
+struct BodySender(mpsc::Sender<io::Result<web::Bytes>>);
+
+impl Write for BodySender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        executor::block_on(self.0.send(Ok(web::Bytes::copy_from_slice(buf))))
+            .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// serve the upload-pack side of smart http in process, other services are not supported
+/// the largest upload-pack request read, it holds the wants and the haves of a negotiation round
+const MAX_UPLOAD_PACK_REQUEST: usize = 16 * 1024 * 1024;
+
+async fn builtin_backend(
+    path_info: String,
+    req: HttpRequest,
+    body: Option<web::Payload>,
+    data: web::Data<Server>,
+) -> spa_server::re_export::Result<HttpResponse> {
+    let repo_path = data.config.read().await.git.index_path.clone();
+    let v2 = req
+        .headers()
+        .get("Git-Protocol")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.contains("version=2"))
+        .unwrap_or(false);
+
+    match (path_info.as_str(), body) {
+        ("/info/refs", None) => {
+            if req.query_string() != "service=git-upload-pack" {
+                return Err(ErrorForbidden("only git-upload-pack service is supported"));
+            }
+
+            let refs = web::block(move || upload_pack::advertise_refs(repo_path, v2))
+                .await
+                .map_err(|e| ErrorInternalServerError(format!("{:?}", e)))?
+                .map_err(|e| ErrorInternalServerError(format!("advertise refs failed: {:?}", e)))?;
+            Ok(HttpResponse::Ok()
+                .content_type("application/x-git-upload-pack-advertisement")
+                .append_header(("Cache-Control", "no-cache"))
+                .body(refs))
+        }
+        ("/git-upload-pack", Some(mut body)) => {
+            let mut request = web::BytesMut::new();
+            while let Some(b) = body.next().await {
+                let b = b?;
+                if request.len() + b.len() > MAX_UPLOAD_PACK_REQUEST {
+                    return Err(ErrorPayloadTooLarge(format!(
+                        "the request is larger than {} bytes",
+                        MAX_UPLOAD_PACK_REQUEST
+                    )));
+                }
+                request.extend_from_slice(&b);
+            }
+
+            let (tx, rx) = mpsc::channel(16);
+            tokio::task::spawn_blocking(move || {
+                let mut out = io::BufWriter::with_capacity(64 * 1024, BodySender(tx.clone()));
+                let result = upload_pack::upload_pack(repo_path, v2, &request, &mut out)
+                    .and_then(|_| Ok(out.flush()?));
+                if let Err(e) = result {
+                    error!("upload-pack failed: {:?}", e);
+                    let _ = executor::block_on(
+                        tx.clone()
+                            .send(Err(io::Error::new(ErrorKind::Other, e.to_string()))),
+                    );
+                }
+            });
+
+            Ok(HttpResponse::Ok()
+                .content_type("application/x-git-upload-pack-result")
+                .append_header(("Cache-Control", "no-cache"))
+                .streaming(rx))
+        }
+        _ => Err(ErrorNotFound(format!("{} is not supported", path_info))),
+    }
+}
+
+/// forward the request to git-http-backend CGI
+async fn cgi_backend(
+    cgi: PathBuf,
+    path_info: String,
+    user: Option<String>,
+    req: HttpRequest,
+    body: Option<web::Payload>,
+    data: web::Data<Server>,
+) -> spa_server::re_export::Result<HttpResponse> {
+    let request_method = req.method().to_string();
+    let mut command = process::Command::new(cgi);
+    command
+        .env("REQUEST_METHOD", request_method)
+        .env("GIT_PROJECT_ROOT", &data.config.read().await.git.index_path)
+        .env("PATH_INFO", path_info)
+        .env("QUERY_STRING", req.query_string())
+        .env("CONTENT_TYPE", req.content_type())
+        .env("GIT_HTTP_EXPORT_ALL", "");
+    // left unset when unknown, git-http-backend only passes them to the hooks and the logs
+    if let Some(user) = user {
+        command.env("REMOTE_USER", user);
+    }
+    if let Some(addr) = req.peer_addr() {
+        command.env("REMOTE_ADDR", addr.ip().to_string());
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or(ErrorInternalServerError("can not open cgi stdin"))?;
+    let mut stdout = tokio::io::BufReader::new(
+        child
+            .stdout
+            .take()
+            .ok_or(ErrorInternalServerError("can not open cgi stdout"))?,
+    );
+    let mut stderr = child
+        .stderr
+        .take()
+        .ok_or(ErrorInternalServerError("can not open cgi stderr"))?;
+    tokio::spawn(async move {
+        let mut err = String::new();
+        if stderr.read_to_string(&mut err).await.is_ok() && !err.is_empty() {
+            error!("cgi error: {}", err.trim());
+        }
+    });
+
+    // feed the request body while reading the response headers, the cgi may start
+    // to respond before the whole request is consumed
+    let feed_stdin = async move {
+        if let Some(mut body) = body {
+            while let Some(b) = body.next().await {
+                stdin.write_all(&*b?).await?;
+            }
+        }
+        drop(stdin);
+        Ok::<_, spa_server::re_export::Error>(())
+    };
+    let (_, (status_code, headers)) =
+        future::try_join(feed_stdin, read_cgi_headers(&mut stdout)).await?;
+    debug!("cgi return:\n{:?}", headers);
+
+    let mut builder = HttpResponse::build(
+        StatusCode::from_u16(status_code)
+            .map_err(|e| ErrorInternalServerError(format!("invalid cgi status code: {:?}", e)))?,
+    );
+    for header in headers {
+        builder.append_header(header);
+    }
+
+    // the body is pulled from cgi stdout only when the client is ready to receive more
+    let body = stream::unfold(Some((stdout, child)), |state| async move {
+        let (mut stdout, mut child) = state?;
+        let mut buf = vec![0u8; CGI_CHUNK_SIZE];
+        match stdout.read(&mut buf).await {
+            Ok(0) => {
+                match child.wait().await {
+                    Ok(status) if !status.success() => error!("cgi exit with {}", status),
+                    Err(e) => error!("wait cgi failed: {:?}", e),
+                    _ => {}
+                }
+                None
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(web::Bytes::from(buf)), Some((stdout, child))))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+
+    Ok(builder.streaming(Box::pin(body)))
+}
+
+const CGI_CHUNK_SIZE: usize = 64 * 1024;
+
+async fn read_cgi_headers(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> spa_server::re_export::Result<(u16, Vec<(String, String)>)> {
+    let mut status_code = 200u16;
+    let mut headers = Vec::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line).await? != 0 {
+        let l = line.trim();
+        if l.is_empty() {
+            // next is body part
+            break;
+        }
+
+        if l.starts_with("Status:") {
+            status_code = l.split(':').collect::<Vec<&str>>()[1]
+                .split(' ')
+                .collect::<Vec<&str>>()[1]
+                .parse()
+                .map_err(|e| ErrorInternalServerError(format!("parse cgi status code: {:?}", e)))?;
+            line.clear();
+            continue;
+        }
+
+        if let Some(i) = l.find(':') {
+            let (k, v) = l.split_at(i + 1);
+            headers.push((k.trim_end_matches(':').to_string(), v.trim().to_string()));
+        } else {
+            return Err(ErrorInternalServerError(format!(
+                "unknown part of cgi response: {:?}",
+                l
+            )));
+        }
+
+        line.clear();
+    }
+
+    Ok((status_code, headers))
+}
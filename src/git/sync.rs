@@ -0,0 +1,404 @@
+use super::{Git, GitCmd};
+use crate::{
+    auth::check,
+    crates_io::shadowed_names,
+    database::{
+        schema::sync_history::{self, dsl},
+        Database, Paginate,
+    },
+    Server,
+};
+use anyhow::{Context, Result};
+use chrono::Local;
+use diesel::prelude::*;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use spa_server::re_export::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized},
+    get,
+    http::header,
+    post, web, HttpRequest, HttpResponse, Identity, Responder,
+};
+use strum::AsRefStr;
+use subtle::ConstantTimeEq;
+use tokio::sync::oneshot;
+
+#[cfg(test)]
+mod test {
+    use super::parse_progress;
+
+    #[test]
+    fn test_parse_progress() {
+        assert_eq!(
+            parse_progress("Receiving objects:  45% (123/273), 1.20 MiB | 2.00 MiB/s"),
+            Some(("Receiving objects".to_string(), Some(45)))
+        );
+        assert_eq!(
+            parse_progress("remote: Counting objects: 100% (10/10), done."),
+            Some(("Counting objects".to_string(), Some(100)))
+        );
+        assert_eq!(
+            parse_progress("remote: Enumerating objects: 27, done."),
+            Some(("Enumerating objects".to_string(), None))
+        );
+        assert_eq!(parse_progress("From /tmp/upstream"), None);
+    }
+}
+
+/// what started a sync
+#[derive(AsRefStr, Clone, Copy)]
+pub(crate) enum SyncSource {
+    Init,
+    Schedule,
+    Manual,
+    Webhook,
+    Import,
+}
+
+/// a fetched index bundle waiting for the sync worker, the result is sent to `done`
+pub(super) struct QueuedImport {
+    /// the crates whose index files are kept as they are here
+    kept: Vec<String>,
+    done: oneshot::Sender<Result<Vec<String>>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "sync_history"]
+struct NewSyncRecord<'a> {
+    source: &'a str,
+    started_at: &'a str,
+}
+
+#[derive(Queryable, Serialize)]
+pub(crate) struct SyncRecord {
+    id: i32,
+    source: String,
+    started_at: String,
+    finished_at: Option<String>,
+    commit_before: Option<String>,
+    commit_after: Option<String>,
+    crates_changed: Option<i32>,
+    error: Option<String>,
+    shadowed: Option<String>,
+}
+
+/// progress of the running sync, updated with the `git pull --progress` output
+#[derive(Serialize, Clone, Default)]
+pub(crate) struct SyncProgress {
+    source: String,
+    started_at: String,
+    stage: Option<String>,
+    percent: Option<u32>,
+    message: Option<String>,
+}
+
+/// parse a progress line of git, eg. `Receiving objects:  45% (123/273), 1.20 MiB | 2.00 MiB/s`,
+/// return the stage and the percent
+fn parse_progress(line: &str) -> Option<(String, Option<u32>)> {
+    let line = line.trim_start_matches("remote:").trim();
+    let (stage, rest) = line.split_at(line.find(':')?);
+    let percent = rest[1..]
+        .trim()
+        .split('%')
+        .next()
+        .and_then(|p| p.parse().ok());
+    if percent.is_none() && !rest.ends_with("done.") {
+        return None;
+    }
+
+    Some((stage.to_string(), percent))
+}
+
+impl Git {
+    /// sync with upstream then push to the index, every sync is recorded in the sync history
+    pub(crate) async fn sync(&self, from: SyncSource) -> Result<()> {
+        self.sync_from(from, None).await.map(|_| ())
+    }
+
+    /// merge the fetched index bundle instead of syncing with the upstream if `kept` is given,
+    /// return the bundled index lines dropped by the merge
+    async fn sync_from(&self, from: SyncSource, kept: Option<&[String]>) -> Result<Vec<String>> {
+        let start = Local::now().to_string();
+        *self.progress.lock().unwrap() = Some(SyncProgress {
+            source: from.as_ref().to_string(),
+            started_at: start.clone(),
+            ..Default::default()
+        });
+
+        let record = {
+            let db = self.database.lock().await;
+            diesel::insert_into(sync_history::table)
+                .values(NewSyncRecord {
+                    source: from.as_ref(),
+                    started_at: &start,
+                })
+                .execute(&db.connection)
+                .and_then(|_| {
+                    dsl::sync_history
+                        .select(dsl::id)
+                        .order(dsl::id.desc())
+                        .first::<i32>(&db.connection)
+                })
+        };
+
+        let before = self.upstream_head().await;
+        let fetched = match kept {
+            Some(kept) => self.merge_bundle(kept).await,
+            None => self.sync_upstream().await.map(|_| Vec::new()),
+        };
+        let result = match fetched {
+            Ok(dropped) => self.sync_index().await.map(|_| dropped),
+            Err(e) => Err(e),
+        };
+        let after = self.upstream_head().await;
+        let changed = match &after {
+            Some(after) => self.changed_crates(before.as_deref(), after).await.ok(),
+            None => Some(0),
+        };
+        let shadowed = match &result {
+            Ok(_) => self.report_shadowed().await,
+            Err(_) => None,
+        };
+        *self.progress.lock().unwrap() = None;
+
+        info!(
+            "sync by {} finished, {:?} -> {:?}, {:?} crates changed",
+            from.as_ref(),
+            before,
+            after,
+            changed
+        );
+        match record {
+            Ok(record) => {
+                if let Err(e) = diesel::update(dsl::sync_history.find(record))
+                    .set((
+                        dsl::finished_at.eq(Local::now().to_string()),
+                        dsl::commit_before.eq(before),
+                        dsl::commit_after.eq(after),
+                        dsl::crates_changed.eq(changed),
+                        dsl::error.eq(result.as_ref().err().map(|e| format!("{:?}", e))),
+                        dsl::shadowed.eq(shadowed),
+                    ))
+                    .execute(&self.database.lock().await.connection)
+                {
+                    error!("update sync history failed: {:?}", e);
+                }
+            }
+            Err(e) => error!("insert sync history failed: {:?}", e),
+        }
+
+        result
+    }
+
+    /// queue a sync, the requests arrive before the queued one starts are merged into it,
+    /// return false if merged
+    pub(crate) async fn request_sync(&self, from: SyncSource) -> bool {
+        let mut queued = self.queued.lock().await;
+        if queued.is_some() {
+            return false;
+        }
+
+        *queued = Some(from);
+        self.wakeup.notify_one();
+        true
+    }
+
+    /// queue a merge of the fetched index bundle and wait for it, the imports are never merged
+    pub(super) async fn request_import(&self, kept: Vec<String>) -> Result<Vec<String>> {
+        let (done, result) = oneshot::channel();
+        self.imports.lock().await.push(QueuedImport { kept, done });
+        self.wakeup.notify_one();
+        result.await.context("the sync worker is gone")?
+    }
+
+    /// run the queued syncs one by one, so there is only one sync at a time
+    pub(super) async fn sync_worker(&self) {
+        loop {
+            self.wakeup.notified().await;
+            let imports = std::mem::take(&mut *self.imports.lock().await);
+            for import in imports {
+                let result = self.sync_from(SyncSource::Import, Some(&import.kept)).await;
+                // the importer may be gone
+                let _ = import.done.send(result);
+            }
+
+            let from = self.queued.lock().await.take();
+            if let Some(from) = from {
+                if let Err(e) = self.sync(from).await {
+                    error!("sync by {} failed: {:?}", from.as_ref(), e);
+                }
+            }
+        }
+    }
+
+    /// feed a progress line of `git pull --progress` to the running sync
+    pub(super) fn update_progress(&self, line: &str) {
+        if let Some((stage, percent)) = parse_progress(line) {
+            if let Some(progress) = self.progress.lock().unwrap().as_mut() {
+                progress.stage = Some(stage);
+                progress.percent = percent;
+                progress.message = Some(line.to_string());
+            }
+        }
+    }
+
+    /// the private crates upstream has claimed since, they are open to dependency confusion
+    async fn report_shadowed(&self) -> Option<String> {
+        match shadowed_names(&self.config, self, &self.upstream, &self.database).await {
+            Ok(names) => {
+                if !names.is_empty() {
+                    warn!(
+                        "private crates {:?} are claimed by upstream now, consider renaming them",
+                        names
+                    );
+                }
+
+                Some(names.join(","))
+            }
+            Err(e) => {
+                error!("check shadowed crates failed: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// whether the upstream index has the file at `relative_path`
+    pub(crate) async fn upstream_has(&self, relative_path: &str) -> bool {
+        GitCmd::dir(&self.config.read().await.git.working_path)
+            .run(format!("cat-file -e upstream/master:{}", relative_path))
+            .is_ok()
+    }
+
+    /// the commits of the work tree and the bare repo
+    pub(crate) async fn heads(&self) -> (Option<String>, Option<String>) {
+        let cfg = self.config.read().await;
+        (
+            GitCmd::dir(&cfg.git.working_path)
+                .run("rev-parse --verify --quiet HEAD")
+                .ok(),
+            GitCmd::dir(&cfg.git.index_path)
+                .run("rev-parse --verify --quiet master")
+                .ok(),
+        )
+    }
+
+    async fn upstream_head(&self) -> Option<String> {
+        GitCmd::dir(&self.config.read().await.git.working_path)
+            .run("rev-parse --verify --quiet upstream/master")
+            .ok()
+    }
+
+    /// the number of index files changed between two upstream commits
+    async fn changed_crates(&self, before: Option<&str>, after: &str) -> Result<i32> {
+        let cfg = self.config.read().await;
+        let files = match before {
+            Some(before) => GitCmd::dir(&cfg.git.working_path)
+                .run(format!("diff --name-only {} {}", before, after)),
+            None => {
+                GitCmd::dir(&cfg.git.working_path).run(format!("ls-tree -r --name-only {}", after))
+            }
+        }
+        .context("list changed index files failed")?;
+
+        Ok(files
+            .lines()
+            .filter(|f| !f.is_empty() && *f != "config.json")
+            .count() as i32)
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HistoryParam {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+/// the sync history, newest first, and the progress of the running sync
+#[get("sync")]
+pub(crate) async fn sync_status(
+    param: web::Query<HistoryParam>,
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    let db = data.database.lock().await;
+    if !check(&id, &db)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let (history, total) = load_history(&db, param.page.unwrap_or(1), param.per_page.unwrap_or(10))
+        .map_err(|e| ErrorInternalServerError(format!("load sync history failed: {:?}", e)))?;
+    let running = data.git.progress.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(json!({
+        "running": running,
+        "history": history,
+        "total": total,
+    })))
+}
+
+fn load_history(db: &Database, page: i64, per_page: i64) -> Result<(Vec<SyncRecord>, i64)> {
+    Ok(dsl::sync_history
+        .order(dsl::id.desc())
+        .paginate(page)
+        .per_page(per_page)
+        .load_and_count::<SyncRecord>(&db.connection)?)
+}
+
+/// queue a sync now
+#[post("sync")]
+pub(crate) async fn sync_now(
+    data: web::Data<Server>,
+    id: Identity,
+) -> spa_server::re_export::Result<impl Responder> {
+    if !check(&id, &*data.database.lock().await)?.is_admin() {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    queue_sync(&data, SyncSource::Manual).await
+}
+
+/// called by the upstream after it updates, authenticated with the `registry.webhook_token`
+/// in `Authorization` header
+#[post("sync/webhook")]
+pub(crate) async fn sync_webhook(
+    req: HttpRequest,
+    data: web::Data<Server>,
+) -> spa_server::re_export::Result<impl Responder> {
+    let token = data
+        .config
+        .read()
+        .await
+        .registry
+        .webhook_token
+        .clone()
+        .ok_or(ErrorNotFound("webhook is not enabled"))?;
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer ").trim());
+    // in constant time, the time taken must not tell how much of the token is guessed right
+    let valid = given.map_or(false, |g| bool::from(g.as_bytes().ct_eq(token.as_bytes())));
+    if !valid {
+        return Err(ErrorUnauthorized("invalid webhook token"));
+    }
+
+    queue_sync(&data, SyncSource::Webhook).await
+}
+
+async fn queue_sync(
+    data: &web::Data<Server>,
+    from: SyncSource,
+) -> spa_server::re_export::Result<HttpResponse> {
+    if !*data.git.inited.lock().await {
+        return Err(ErrorBadRequest("System not initialized"));
+    }
+
+    let queued = data.git.request_sync(from).await;
+    info!(
+        "sync requested by {}, {}",
+        from.as_ref(),
+        if queued { "queued" } else { "merged" }
+    );
+    Ok(HttpResponse::Accepted().json(json!({ "queued": queued })))
+}
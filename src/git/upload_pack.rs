@@ -0,0 +1,527 @@
+use anyhow::{anyhow, bail, Context, Result};
+use gix::{hash::ObjectId, object::Kind, progress, Reference, Repository};
+use gix_pack::data::{output, Version};
+use log::debug;
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::atomic::AtomicBool,
+};
+
+#[cfg(test)]
+mod test {
+    use super::{packets, parse_fetch_v2, Packet};
+
+    #[test]
+    fn test_packets() {
+        let body = b"0032want 0123456789012345678901234567890123456789\n00000009done\n";
+        let result = packets(body).unwrap();
+        assert_eq!(result.len(), 3);
+        assert!(
+            matches!(result[0], Packet::Data(d) if d == &b"want 0123456789012345678901234567890123456789\n"[..])
+        );
+        assert!(matches!(result[1], Packet::Flush));
+        assert!(matches!(result[2], Packet::Data(d) if d == &b"done\n"[..]));
+        assert!(packets(b"0032want").is_err());
+    }
+
+    #[test]
+    fn test_parse_fetch_v2() {
+        let want = "want 0123456789012345678901234567890123456789";
+        let args = parse_fetch_v2(&[want, "thin-pack", "ofs-delta", "no-progress", "include-tag"])
+            .unwrap();
+        assert_eq!(args.wants.len(), 1);
+        assert!(args.include_tag);
+        assert!(!args.done);
+        assert!(parse_fetch_v2(&[want, "deepen 1"]).is_err());
+        assert!(parse_fetch_v2(&[want, "sideband-all"]).is_err());
+    }
+}
+
+const AGENT: &str = concat!("agent=mirror-registry/", env!("CARGO_PKG_VERSION"));
+const CAPABILITIES: &str = "multi_ack_detailed side-band-64k ofs-delta no-progress";
+/// max data length of a side-band-64k packet, 65520 minus length header and band number
+const MAX_SIDE_BAND_DATA: usize = 65515;
+
+enum Packet<'a> {
+    Flush,
+    Delim,
+    Data(&'a [u8]),
+}
+
+fn packets(mut body: &[u8]) -> Result<Vec<Packet<'_>>> {
+    let mut result = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 4 {
+            bail!("truncated pkt-line length");
+        }
+
+        let len = usize::from_str_radix(std::str::from_utf8(&body[..4])?, 16)
+            .context("invalid pkt-line length")?;
+        match len {
+            0 => result.push(Packet::Flush),
+            1 => result.push(Packet::Delim),
+            2 | 3 => bail!("unsupported pkt-line length {}", len),
+            _ => {
+                if body.len() < len {
+                    bail!("truncated pkt-line data");
+                }
+                result.push(Packet::Data(&body[4..len]));
+                body = &body[len..];
+                continue;
+            }
+        }
+        body = &body[4..];
+    }
+
+    Ok(result)
+}
+
+fn pkt_line(out: &mut impl Write, data: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let data = data.as_ref();
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.write_all(data)
+}
+
+fn flush_pkt(out: &mut impl Write) -> std::io::Result<()> {
+    out.write_all(b"0000")
+}
+
+fn delim_pkt(out: &mut impl Write) -> std::io::Result<()> {
+    out.write_all(b"0001")
+}
+
+/// wrap the pack data into side-band-64k packets on band 1
+struct SideBand<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Write for SideBand<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(MAX_SIDE_BAND_DATA);
+        write!(self.inner, "{:04x}\x01", len + 5)?;
+        self.inner.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn parse_oid(hex: &str) -> Result<ObjectId> {
+    ObjectId::from_hex(hex.trim().as_bytes())
+        .map_err(|e| anyhow!("invalid object id {}: {}", hex, e))
+}
+
+struct Ref {
+    name: String,
+    /// the object the ref points to, an annotated tag is not peeled
+    id: ObjectId,
+    /// the object at the end of the tags
+    peeled: ObjectId,
+    /// the ref pointed to by a symbolic ref
+    symref_target: Option<String>,
+}
+
+/// the object a ref points to, following the symbolic refs only
+fn target_id(r: &Reference<'_>) -> Result<ObjectId> {
+    match r.follow() {
+        Some(next) => target_id(&next.map_err(|e| anyhow!("read reference failed: {}", e))?),
+        None => Ok(r.id().detach()),
+    }
+}
+
+fn refs(repo: &Repository) -> Result<Vec<Ref>> {
+    let mut result = Vec::new();
+    let platform = repo.references()?;
+    for r in platform.all()? {
+        let r = r.map_err(|e| anyhow!("read reference failed: {}", e))?;
+        // peel first, it fails on the loops of symbolic refs
+        let peeled = r.clone().into_fully_peeled_id()?.detach();
+        result.push(Ref {
+            name: r.name().as_bstr().to_string(),
+            id: target_id(&r)?,
+            peeled,
+            symref_target: r.target().try_name().map(|n| n.as_bstr().to_string()),
+        });
+    }
+
+    Ok(result)
+}
+
+fn head(repo: &Repository) -> Result<Option<(String, ObjectId)>> {
+    let name = match repo.head_name()? {
+        Some(n) => n.as_bstr().to_string(),
+        None => return Ok(None),
+    };
+
+    Ok(repo.head_id().ok().map(|id| (name, id.detach())))
+}
+
+/// the response of `GET info/refs?service=git-upload-pack`
+pub(crate) fn advertise_refs(repo_path: impl AsRef<Path>, v2: bool) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    if v2 {
+        pkt_line(&mut out, "version 2\n")?;
+        pkt_line(&mut out, format!("{}\n", AGENT))?;
+        pkt_line(&mut out, "ls-refs\n")?;
+        pkt_line(&mut out, "fetch\n")?;
+        pkt_line(&mut out, "object-format=sha1\n")?;
+        flush_pkt(&mut out)?;
+        return Ok(out);
+    }
+
+    let repo = gix::open(repo_path.as_ref())?;
+    pkt_line(&mut out, "# service=git-upload-pack\n")?;
+    flush_pkt(&mut out)?;
+
+    let head = head(&repo)?;
+    let mut caps = format!("{} {}", CAPABILITIES, AGENT);
+    let mut lines = Vec::new();
+    if let Some((target, id)) = &head {
+        caps = format!("{} symref=HEAD:{}", caps, target);
+        lines.push(format!("{} HEAD", id));
+    }
+
+    for r in refs(&repo)? {
+        lines.push(format!("{} {}", r.id, r.name));
+        if r.peeled != r.id {
+            lines.push(format!("{} {}^{{}}", r.peeled, r.name));
+        }
+    }
+
+    if lines.is_empty() {
+        lines.push(format!(
+            "{} capabilities^{{}}",
+            ObjectId::null(gix::hash::Kind::Sha1)
+        ));
+    }
+
+    for (i, line) in lines.into_iter().enumerate() {
+        if i == 0 {
+            pkt_line(&mut out, format!("{}\0{}\n", line, caps))?;
+        } else {
+            pkt_line(&mut out, format!("{}\n", line))?;
+        }
+    }
+    flush_pkt(&mut out)?;
+
+    Ok(out)
+}
+
+#[derive(Default)]
+struct FetchArgs {
+    wants: Vec<ObjectId>,
+    haves: Vec<ObjectId>,
+    done: bool,
+    side_band: bool,
+    /// send the annotated tags pointing to the commits sent
+    include_tag: bool,
+}
+
+/// the response of `POST git-upload-pack`, the pack data is written to `out` on the fly
+pub(crate) fn upload_pack(
+    repo_path: impl AsRef<Path>,
+    v2: bool,
+    body: &[u8],
+    out: &mut impl Write,
+) -> Result<()> {
+    let repo = gix::open(repo_path.as_ref())?;
+    let packets = packets(body)?;
+    if !v2 {
+        return fetch(&repo, parse_fetch_v0(&packets)?, false, out);
+    }
+
+    let mut command = None;
+    let mut args = Vec::new();
+    let mut in_args = false;
+    for p in &packets {
+        match p {
+            Packet::Delim => in_args = true,
+            Packet::Flush => break,
+            Packet::Data(d) => {
+                let line = std::str::from_utf8(d)?.trim_end_matches('\n');
+                if in_args {
+                    args.push(line);
+                } else if let Some(c) = line.strip_prefix("command=") {
+                    command = Some(c);
+                }
+            }
+        }
+    }
+
+    match command {
+        Some("ls-refs") => ls_refs(&repo, &args, out),
+        Some("fetch") => fetch(&repo, parse_fetch_v2(&args)?, true, out),
+        Some(c) => bail!("unsupported command {}", c),
+        None => bail!("no command in request"),
+    }
+}
+
+fn parse_fetch_v0(packets: &[Packet]) -> Result<FetchArgs> {
+    let mut args = FetchArgs::default();
+    for p in packets {
+        if let Packet::Data(d) = p {
+            let line = std::str::from_utf8(d)?.trim_end_matches('\n');
+            if let Some(want) = line.strip_prefix("want ") {
+                let mut parts = want.split(' ');
+                args.wants.push(parse_oid(parts.next().unwrap_or(""))?);
+                for cap in parts {
+                    if cap == "side-band-64k" || cap == "side-band" {
+                        args.side_band = true;
+                    }
+                }
+            } else if let Some(have) = line.strip_prefix("have ") {
+                args.haves.push(parse_oid(have)?);
+            } else if line == "done" {
+                args.done = true;
+            } else {
+                bail!("unsupported upload-pack request: {}", line);
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+fn parse_fetch_v2(lines: &[&str]) -> Result<FetchArgs> {
+    let mut args = FetchArgs {
+        side_band: true,
+        ..Default::default()
+    };
+    for line in lines {
+        if let Some(want) = line.strip_prefix("want ") {
+            args.wants.push(parse_oid(want)?);
+        } else if let Some(have) = line.strip_prefix("have ") {
+            args.haves.push(parse_oid(have)?);
+        } else if *line == "done" {
+            args.done = true;
+        } else if *line == "include-tag" {
+            args.include_tag = true;
+        } else if *line == "thin-pack" || *line == "ofs-delta" || *line == "no-progress" {
+            // a full pack is also fine for thin-pack, and no progress is ever sent
+        } else {
+            bail!("unsupported fetch argument: {}", line);
+        }
+    }
+
+    Ok(args)
+}
+
+fn ls_refs(repo: &Repository, args: &[&str], out: &mut impl Write) -> Result<()> {
+    let mut prefixes = Vec::new();
+    let (mut symrefs, mut peel) = (false, false);
+    for arg in args {
+        match *arg {
+            "symrefs" => symrefs = true,
+            "peel" => peel = true,
+            _ => match arg.strip_prefix("ref-prefix ") {
+                Some(prefix) => prefixes.push(prefix),
+                None => bail!("unsupported ls-refs argument: {}", arg),
+            },
+        }
+    }
+    let matches = |name: &str| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+
+    if let Some((target, id)) = head(repo)? {
+        if matches("HEAD") {
+            if symrefs {
+                pkt_line(out, format!("{} HEAD symref-target:{}\n", id, target))?;
+            } else {
+                pkt_line(out, format!("{} HEAD\n", id))?;
+            }
+        }
+    }
+
+    for r in refs(repo)? {
+        if !matches(&r.name) {
+            continue;
+        }
+
+        let mut line = format!("{} {}", r.id, r.name);
+        if let (true, Some(target)) = (symrefs, &r.symref_target) {
+            line = format!("{} symref-target:{}", line, target);
+        }
+        if peel && r.peeled != r.id {
+            line = format!("{} peeled:{}", line, r.peeled);
+        }
+        pkt_line(out, format!("{}\n", line))?;
+    }
+
+    flush_pkt(out)?;
+    Ok(())
+}
+
+fn fetch(repo: &Repository, args: FetchArgs, v2: bool, out: &mut impl Write) -> Result<()> {
+    if args.wants.is_empty() {
+        bail!("no want in fetch request");
+    }
+
+    let common: Vec<ObjectId> = args
+        .haves
+        .iter()
+        .filter(|h| repo.find_object(**h).is_ok())
+        .cloned()
+        .collect();
+
+    // we are always ready once some common commits are found, a bit more objects may be sent
+    // than what git upload-pack does, but less round trips.
+    if v2 {
+        if !args.done {
+            pkt_line(out, "acknowledgments\n")?;
+            if common.is_empty() {
+                pkt_line(out, "NAK\n")?;
+                flush_pkt(out)?;
+                return Ok(());
+            }
+
+            for c in &common {
+                pkt_line(out, format!("ACK {}\n", c))?;
+            }
+            pkt_line(out, "ready\n")?;
+            delim_pkt(out)?;
+        }
+
+        pkt_line(out, "packfile\n")?;
+    } else {
+        for c in &common {
+            pkt_line(out, format!("ACK {} common\n", c))?;
+        }
+
+        if !args.done {
+            if let Some(c) = common.last() {
+                pkt_line(out, format!("ACK {} ready\n", c))?;
+            }
+            pkt_line(out, "NAK\n")?;
+            return Ok(());
+        }
+
+        match common.last() {
+            Some(c) => pkt_line(out, format!("ACK {}\n", c))?,
+            None => pkt_line(out, "NAK\n")?,
+        }
+    }
+
+    // a wanted tag is sent with its commit
+    let mut tags = Vec::new();
+    let mut wants = Vec::new();
+    for want in &args.wants {
+        let object = repo
+            .find_object(*want)
+            .with_context(|| format!("object {} not found", want))?;
+        if object.kind == Kind::Tag {
+            tags.push(*want);
+            wants.push(object.peel_tags_to_end()?.id);
+        } else {
+            wants.push(*want);
+        }
+    }
+
+    let commits = commits_to_send(repo, &wants, &common)?;
+    if args.include_tag {
+        let sent: HashSet<_> = commits.iter().collect();
+        for r in refs(repo)? {
+            if r.peeled != r.id && sent.contains(&r.peeled) && !tags.contains(&r.id) {
+                tags.push(r.id);
+            }
+        }
+    }
+    let commits = commits.into_iter().chain(tags).collect::<Vec<_>>();
+    if args.side_band {
+        let mut writer =
+            BufWriter::with_capacity(MAX_SIDE_BAND_DATA, SideBand { inner: &mut *out });
+        write_pack(repo, commits, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        flush_pkt(out)?;
+    } else {
+        let mut writer = BufWriter::new(&mut *out);
+        write_pack(repo, commits, &mut writer)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// all the commits reachable from `wants` but not from `common`
+fn commits_to_send(
+    repo: &Repository,
+    wants: &[ObjectId],
+    common: &[ObjectId],
+) -> Result<Vec<ObjectId>> {
+    let mut hidden = HashSet::new();
+    walk(repo, common, &mut hidden, |_| {})?;
+
+    let mut result = Vec::new();
+    walk(repo, wants, &mut hidden, |id| result.push(id))?;
+    debug!("upload-pack {} commits to send", result.len());
+    Ok(result)
+}
+
+fn walk(
+    repo: &Repository,
+    tips: &[ObjectId],
+    seen: &mut HashSet<ObjectId>,
+    mut f: impl FnMut(ObjectId),
+) -> Result<()> {
+    let mut queue: VecDeque<ObjectId> = tips.iter().cloned().collect();
+    while let Some(id) = queue.pop_front() {
+        if !seen.insert(id) {
+            continue;
+        }
+
+        let commit = repo
+            .find_object(id)
+            .with_context(|| format!("object {} not found", id))?
+            .try_into_commit()
+            .with_context(|| format!("object {} is not a commit", id))?;
+        for parent in commit.parent_ids() {
+            queue.push_back(parent.detach());
+        }
+        f(id);
+    }
+
+    Ok(())
+}
+
+/// pack `commits`, the tags in them are packed with the objects they point to
+fn write_pack(repo: &Repository, commits: Vec<ObjectId>, out: &mut impl Write) -> Result<()> {
+    let mut db = gix::odb::at(repo.git_dir().join("objects"))?.into_arc()?;
+    db.prevent_pack_unload();
+    let interrupt = AtomicBool::new(false);
+
+    let (counts, _) = output::count::objects(
+        db.clone(),
+        Box::new(commits.into_iter().map(Ok)),
+        &progress::Discard,
+        &interrupt,
+        output::count::objects::Options {
+            input_object_expansion:
+                output::count::objects::ObjectExpansion::TreeAdditionsComparedToAncestor,
+            ..Default::default()
+        },
+    )?;
+    let num_entries = counts.len() as u32;
+    debug!("upload-pack {} objects to send", num_entries);
+
+    let entries = gix::features::parallel::InOrderIter::from(output::entry::iter_from_counts(
+        counts,
+        db,
+        Box::new(progress::Discard),
+        Default::default(),
+    ));
+    let mut pack = output::bytes::FromEntriesIter::new(
+        entries,
+        out,
+        num_entries,
+        Version::V2,
+        gix::hash::Kind::Sha1,
+    );
+    for r in pack.by_ref() {
+        r?;
+    }
+
+    Ok(())
+}
@@ -3,6 +3,7 @@
 //! to mirror upstream registry and serve private crates
 //! # Features
 //! - Mirror upstream crates.io-index 
+//! - Serve the index over both git and sparse protocol
 //! - Caching download crates from crates.io (or other upstream)
 //! - Support full [Registry Web API](https://doc.rust-lang.org/cargo/reference/registries.html#web-api) for private crates
 //!     * cargo login   (login for publish)
@@ -41,6 +42,13 @@
 //! [source.mirror]
 //! registry = "http://localhost:55555/registry/crates.io-index"
 //! ```
+//! - or use the sparse protocol, cargo only fetches the index files it needs
+//! ```
+//! [source.mirror]
+//! registry = "sparse+http://localhost:55555/index/"
+//! ```
+//! - turn on `auth_required` in the web ui, then the index and crate downloads need a token,
+//!   sparse protocol is recommended, run `cargo login` first (git asks the token as password)
 //! 
 //! # License
 //! This project is licensed under either 
@@ -52,6 +60,8 @@ mod config;
 mod crates_io;
 mod database;
 mod git;
+mod sparse;
+mod storage;
 #[macro_use]
 extern crate diesel_migrations;
 #[macro_use]
@@ -59,14 +69,16 @@ extern crate diesel;
 
 use crate::config::{Config, DEFAULT_PORT};
 use auth::AuthContext;
-use crates_io::Index;
+use crates_io::{Downloads, Index, PrefetchProgress, PrefetchRequest, Sources};
 use database::Database;
 use git::Git;
 use spa_server::{
     re_export::{get, HttpResponse, Responder},
     SPAServer,
 };
-use std::sync::Arc;
+use std::{fs, path::PathBuf, sync::Arc};
+use storage::Storage;
+use structopt::StructOpt;
 use tokio::sync::{Mutex, RwLock};
 
 #[derive(SPAServer)]
@@ -83,13 +95,36 @@ use tokio::sync::{Mutex, RwLock};
             crates_io::list_owners,
             crates_io::add_owner,
             crates_io::remove_owner,
+            crates_io::crate_downloads,
+            crates_io::list_versions,
+            crates_io::version_dependencies,
+            crates_io::version_readme,
+            crates_io::version_files,
+            crates_io::version_detail,
+            crates_io::crate_detail,
         ),
         api(prefix = "/registry", git::http_backend_get, git::http_backend_post),
+        api(prefix = "/index", sparse::config_json, sparse::index_file),
         api(
             prefix = "/web_api",
             config::get_config,
             config::set_config,
             config::init,
+            git::sync_status,
+            git::sync_now,
+            git::sync_webhook,
+            crates_io::verify_storage,
+            crates_io::start_prefetch,
+            crates_io::prefetch_status,
+            crates_io::cache_usage,
+            crates_io::evict_cache,
+            crates_io::list_sources,
+            crates_io::list_shadow,
+            crates_io::allow_shadow,
+            crates_io::disallow_shadow,
+            crates_io::list_allowed,
+            crates_io::allow_metadata,
+            crates_io::disallow_metadata,
         ),
         api(me),
         api(
@@ -107,10 +142,95 @@ use tokio::sync::{Mutex, RwLock};
 )]
 pub struct Server {
     git: Arc<Git>,
-    database: Mutex<Database>,
+    database: Arc<Mutex<Database>>,
     config: Arc<RwLock<Config>>,
     auth_context: AuthContext,
     index: Index,
+    storage: Arc<dyn Storage>,
+    prefetch: std::sync::Mutex<Option<PrefetchProgress>>,
+    downloads: Downloads,
+    sources: Sources,
+}
+
+impl Server {
+    async fn new(
+        config: Arc<RwLock<Config>>,
+        database: Arc<Mutex<Database>>,
+    ) -> anyhow::Result<Self> {
+        let index = Index::new(config.clone());
+        Ok(Server {
+            git: Git::new(config.clone(), database.clone(), index.upstream().clone()).await?,
+            database,
+            auth_context: AuthContext::new().await?,
+            index,
+            storage: storage::new(config.clone()).await?,
+            prefetch: std::sync::Mutex::new(None),
+            downloads: Default::default(),
+            sources: Default::default(),
+            config,
+        })
+    }
+}
+
+#[derive(StructOpt)]
+struct Opt {
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(StructOpt)]
+enum Cmd {
+    /// check the index, crate storage, database and git repos agree with each other,
+    /// print the report in json
+    Verify {
+        /// move the corrupted crate files to the quarantine directory and sync the bare repo with
+        /// the work tree
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// download crates from upstream into the storage ahead of time, print the result in json,
+    /// eg. `prefetch --lockfile Cargo.lock serde@1.0 --all rand`
+    Prefetch {
+        /// the registry packages in this Cargo.lock
+        #[structopt(long, parse(from_os_str))]
+        lockfile: Option<PathBuf>,
+        /// all the versions of these crates
+        #[structopt(long = "all")]
+        all_versions: Vec<String>,
+        /// the number of concurrent downloads
+        #[structopt(long, default_value = "8")]
+        jobs: usize,
+        /// `name@req`, the newest version matches the requirement
+        crates: Vec<String>,
+    },
+    /// pack the index, the chosen crate files and the private crates into a bundle for
+    /// the offline mirrors, print the manifest in json. stop the server first, they share the
+    /// index and the database
+    Export {
+        /// the bundle file to write, eg. mirror.tar.gz
+        #[structopt(long, short, parse(from_os_str))]
+        output: PathBuf,
+        /// only the changes after this index commit, the `commit` in the manifest of the last bundle
+        #[structopt(long)]
+        since: Option<String>,
+        /// every crate file in storage
+        #[structopt(long)]
+        cached: bool,
+        /// the registry packages in this Cargo.lock
+        #[structopt(long, parse(from_os_str))]
+        lockfile: Option<PathBuf>,
+        /// all the versions of these crates
+        #[structopt(long = "all")]
+        all_versions: Vec<String>,
+        /// `name@req`, the newest version matches the requirement
+        crates: Vec<String>,
+    },
+    /// apply a bundle made by `export`, in the order they are made. stop the server first, they
+    /// share the index and the database
+    Import {
+        #[structopt(parse(from_os_str))]
+        bundle: PathBuf,
+    },
 }
 
 #[get("me")]
@@ -122,22 +242,99 @@ async fn me() -> spa_server::re_export::Result<impl Responder> {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
+    let opt = Opt::from_args();
     let config = Arc::new(RwLock::new(Config::new()?));
-    let database = Database::new(config.clone()).await?;
+    let database = Arc::new(Mutex::new(Database::new(config.clone()).await?));
+
+    if let Some(cmd) = opt.cmd {
+        let server = Arc::new(Server::new(config, database).await?);
+        let clean = match cmd {
+            Cmd::Verify { repair } => {
+                let report = crates_io::verify(
+                    &server.config,
+                    &server.git,
+                    &server.database,
+                    &*server.storage,
+                    repair,
+                )
+                .await?;
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                report.is_clean()
+            }
+            Cmd::Prefetch {
+                lockfile,
+                all_versions,
+                jobs,
+                crates,
+            } => {
+                let request = PrefetchRequest {
+                    lockfile: match lockfile {
+                        Some(path) => Some(fs::read_to_string(path)?),
+                        None => None,
+                    },
+                    crates,
+                    all_versions,
+                    jobs: Some(jobs),
+                };
+                crates_io::begin_prefetch(&server);
+                let progress = crates_io::prefetch(&server, request.targets()?, jobs).await;
+                println!("{}", serde_json::to_string_pretty(&progress)?);
+                progress.map_or(false, |p| p.is_clean())
+            }
+            Cmd::Export {
+                output,
+                since,
+                cached,
+                lockfile,
+                all_versions,
+                crates,
+            } => {
+                let request = PrefetchRequest {
+                    lockfile: match lockfile {
+                        Some(path) => Some(fs::read_to_string(path)?),
+                        None => None,
+                    },
+                    crates,
+                    all_versions,
+                    jobs: None,
+                };
+                let manifest = crates_io::export(
+                    &server,
+                    &output,
+                    since.as_deref(),
+                    request.targets()?,
+                    cached,
+                )
+                .await?;
+                println!("{}", serde_json::to_string_pretty(&manifest)?);
+                true
+            }
+            Cmd::Import { bundle } => {
+                let manifest = crates_io::import(&server, &bundle).await?;
+                println!("{}", serde_json::to_string_pretty(&manifest)?);
+                true
+            }
+        };
+
+        if !clean {
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
 
     println!(
         "open the mirror registry web on {} for further settings",
         config.read().await.registry.address
     );
-    Server {
-        git: Git::new(config.clone()).await?,
-        database: Mutex::new(database),
-        auth_context: AuthContext::new().await?,
-        index: Index::new(config.clone()),
-        config,
-    }
-    .run(DEFAULT_PORT)
-    .await?;
+    let server = Server::new(config, database).await?;
+    crates_io::schedule_eviction(
+        server.config.clone(),
+        server.database.clone(),
+        server.storage.clone(),
+    );
+    crates_io::schedule_refresh(server.database.clone());
+    server.run(DEFAULT_PORT).await?;
 
     Ok(())
 }
@@ -0,0 +1,100 @@
+use crate::{auth::check_registry_token, crates_io::relative_path, git::IndexConfig, Server};
+use log::debug;
+use sha2::{Digest, Sha256};
+use spa_server::re_export::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound},
+    get,
+    http::header,
+    web, HttpRequest, HttpResponse, Result,
+};
+
+#[cfg(test)]
+mod test {
+    use super::is_valid_name;
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("serde"));
+        assert!(is_valid_name("serde_json"));
+        assert!(is_valid_name("tokio-util"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name("a/b"));
+        assert!(!is_valid_name("中文"));
+    }
+}
+
+/// only ascii alphanumeric, `-` and `_` can appear in a crate name, anything else
+/// is rejected before touching the file system
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[get("/config.json")]
+pub(crate) async fn config_json(req: HttpRequest, data: web::Data<Server>) -> Result<HttpResponse> {
+    if !*data.git.inited.lock().await {
+        return Err(ErrorBadRequest("System not initialized"));
+    }
+
+    check_registry_token(&req, &data).await?;
+
+    Ok(HttpResponse::Ok().json(IndexConfig::new(&*data.config.read().await)))
+}
+
+/// serve the index file, the path layout is the same as the git index,
+/// eg. `/index/se/rd/serde`, `/index/3/s/syn`.
+/// in lazy mirror mode the upstream index file is fetched on demand and merged with the
+/// private crates in work tree.
+#[get("/{prefix:.+}/{name}")]
+pub(crate) async fn index_file(
+    req: HttpRequest,
+    info: web::Path<(String, String)>,
+    data: web::Data<Server>,
+) -> Result<HttpResponse> {
+    if !*data.git.inited.lock().await {
+        return Err(ErrorBadRequest("System not initialized"));
+    }
+
+    check_registry_token(&req, &data).await?;
+
+    let (prefix, name) = info.into_inner();
+    if !is_valid_name(&name) {
+        return Err(ErrorNotFound(format!("invalid crate name: {}", name)));
+    }
+
+    if format!("{}/{}", prefix, name) != relative_path(&name) {
+        return Err(ErrorNotFound(format!(
+            "index path {}/{} not match crate {}",
+            prefix, name, name
+        )));
+    }
+
+    debug!("sparse index request for {}", name);
+    let content = data
+        .index
+        .read(&name)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("{:?}", e)))?
+        .ok_or_else(|| ErrorNotFound(format!("crate {} not found", name)))?;
+
+    let etag = format!("\"{:x}\"", Sha256::digest(&content));
+    let not_modified = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').any(|t| t.trim() == etag))
+        .unwrap_or_default();
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish());
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain")
+        .insert_header((header::ETAG, etag))
+        .body(content))
+}
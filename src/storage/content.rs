@@ -0,0 +1,260 @@
+use super::{hash_file, ByteStream, Object, Storage};
+use crate::database::{schema::crate_blobs, Database};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use log::info;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, future::Future, path::Path};
+use tokio::sync::Mutex;
+
+/// the prefix of the blob keys in the inner storage, no crate name starts with `.` so no crate
+/// key is under it
+const BLOB_PREFIX: &str = ".blobs/sha256/";
+
+#[cfg(test)]
+mod test {
+    use super::{blob_key, not_blobs};
+    use crate::storage::Object;
+    use chrono::Utc;
+
+    #[test]
+    fn test_blob_key() {
+        assert_eq!(
+            blob_key("558dc50e1a5a5fa7112ca2ce4effcb321b0300c0d4ccf0776a9f60cd89031171"),
+            ".blobs/sha256/55/558dc50e1a5a5fa7112ca2ce4effcb321b0300c0d4ccf0776a9f60cd89031171"
+        );
+    }
+
+    #[test]
+    fn test_not_blobs() {
+        let object = |key: &str| Object {
+            key: key.to_string(),
+            size: 1,
+            modified: Utc::now(),
+        };
+        let listed = not_blobs(vec![
+            object("sha256/sha256-1.0.0.crate"),
+            object(&blob_key(
+                "558dc50e1a5a5fa7112ca2ce4effcb321b0300c0d4ccf0776a9f60cd89031171",
+            )),
+        ]);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, "sha256/sha256-1.0.0.crate");
+    }
+}
+
+/// which blob a key maps to
+#[derive(Queryable, Insertable)]
+#[table_name = "crate_blobs"]
+struct BlobRecord {
+    key: String,
+    cksum: String,
+    size: i64,
+    created_at: NaiveDateTime,
+}
+
+/// eg. `.blobs/sha256/55/558dc50e...`, the same as the `cksum` in the index
+fn blob_key(cksum: &str) -> String {
+    format!("{}{}/{}", BLOB_PREFIX, &cksum[..2], cksum)
+}
+
+/// the objects in the inner storage except the blobs
+fn not_blobs(objects: Vec<Object>) -> Vec<Object> {
+    objects
+        .into_iter()
+        .filter(|o| !o.key.starts_with(BLOB_PREFIX))
+        .collect()
+}
+
+/// the crate files are stored once per content in the inner storage, named by their sha256,
+/// and the keys like `serde/serde-1.0.0.crate` map to them in the database.
+/// the files stored by an older version are moved into the blobs when they are read.
+pub(super) struct ContentAddressed {
+    inner: Box<dyn Storage>,
+    /// a connection of its own, the storage is used while the server one is locked
+    database: Mutex<Database>,
+    /// the blobs being uploaded and the number of uploads of each, they are not deleted when
+    /// released meanwhile. held by `put` and `delete` from checking a blob to changing the
+    /// mappings of it, so a blob is never deleted when a key is just mapped to it, but not while
+    /// uploading. the readers do not wait for it
+    writing: Mutex<HashMap<String, usize>>,
+}
+
+impl ContentAddressed {
+    pub(super) fn new(inner: Box<dyn Storage>, database: Database) -> Self {
+        ContentAddressed {
+            inner,
+            database: Mutex::new(database),
+            writing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn find(&self, key: &str) -> Result<Option<BlobRecord>> {
+        Ok(crate_blobs::table
+            .find(key)
+            .first::<BlobRecord>(&self.database.lock().await.connection)
+            .optional()?)
+    }
+
+    /// replace the mapping of `key` with `cksum`, or remove it if `None`, return the blob
+    /// mapped before if no key maps to it any more. it is counted in the same transaction
+    async fn remap(&self, key: &str, cksum: Option<&str>, size: i64) -> Result<Option<String>> {
+        let db = self.database.lock().await;
+        let connection = &db.connection;
+        connection.transaction::<_, anyhow::Error, _>(|| {
+            let old = crate_blobs::table
+                .find(key)
+                .first::<BlobRecord>(connection)
+                .optional()?;
+            match cksum {
+                Some(cksum) => {
+                    diesel::replace_into(crate_blobs::table)
+                        .values(BlobRecord {
+                            key: key.to_string(),
+                            cksum: cksum.to_string(),
+                            size,
+                            created_at: Utc::now().naive_utc(),
+                        })
+                        .execute(connection)?;
+                }
+                None => {
+                    diesel::delete(crate_blobs::table.find(key)).execute(connection)?;
+                }
+            }
+
+            let old = match old {
+                Some(old) if Some(old.cksum.as_str()) != cksum => old.cksum,
+                _ => return Ok(None),
+            };
+            let refs: i64 = crate_blobs::table
+                .filter(crate_blobs::cksum.eq(&old))
+                .count()
+                .get_result(connection)?;
+            Ok(if refs == 0 { Some(old) } else { None })
+        })
+    }
+
+    /// change the mapping of `key` like `remap`, and delete the blob released unless it is
+    /// being uploaded. `writing` is held
+    async fn map(
+        &self,
+        uploading: &HashMap<String, usize>,
+        key: &str,
+        cksum: Option<&str>,
+        size: i64,
+    ) -> Result<()> {
+        match self.remap(key, cksum, size).await? {
+            Some(released) if !uploading.contains_key(&released) => {
+                self.inner.delete(&blob_key(&released)).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// upload the blob of `cksum` with `upload` unless it is stored, then map `key` to it
+    async fn put_blob<F, Fut>(&self, key: &str, cksum: &str, size: i64, upload: F) -> Result<()>
+    where
+        F: FnOnce(String) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        {
+            let mut uploading = self.writing.lock().await;
+            if self.inner.exists(&blob_key(cksum)).await? {
+                return self.map(&uploading, key, Some(cksum), size).await;
+            }
+            *uploading.entry(cksum.to_string()).or_default() += 1;
+        }
+
+        let uploaded = upload(blob_key(cksum)).await;
+        let mut uploading = self.writing.lock().await;
+        if let Some(n) = uploading.get_mut(cksum) {
+            *n -= 1;
+            if *n == 0 {
+                uploading.remove(cksum);
+            }
+        }
+        uploaded?;
+        self.map(&uploading, key, Some(cksum), size).await
+    }
+}
+
+#[async_trait]
+impl Storage for ContentAddressed {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(record) = self.find(key).await? {
+            return self.inner.get(&blob_key(&record.cksum)).await;
+        }
+
+        let legacy = self.inner.get(key).await?;
+        if let Some(data) = &legacy {
+            info!("move {} into the blobs", key);
+            self.put(key, data.clone()).await?;
+            self.inner.delete(key).await?;
+        }
+        Ok(legacy)
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<Option<ByteStream>> {
+        // a file not moved into the blobs yet is moved by `get` first
+        if self.find(key).await?.is_none() && self.get(key).await?.is_none() {
+            return Ok(None);
+        }
+
+        match self.find(key).await? {
+            Some(record) => self.inner.get_stream(&blob_key(&record.cksum)).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match self.find(key).await? {
+            Some(record) => self.inner.exists(&blob_key(&record.cksum)).await,
+            None => self.inner.exists(key).await,
+        }
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let cksum = format!("{:x}", Sha256::digest(&data));
+        let size = data.len() as i64;
+        self.put_blob(key, &cksum, size, |blob| async move {
+            self.inner.put(&blob, data).await
+        })
+        .await
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        let (cksum, size) = hash_file(path).await?;
+        self.put_blob(key, &cksum, size as i64, |blob| async move {
+            self.inner.put_file(&blob, path).await
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let uploading = self.writing.lock().await;
+        if self.find(key).await?.is_none() {
+            return self.inner.delete(key).await;
+        }
+
+        self.map(&uploading, key, None, 0).await
+    }
+
+    /// the mapped keys and the files not moved into the blobs yet, the blobs are not listed
+    async fn list(&self) -> Result<Vec<Object>> {
+        let records =
+            crate_blobs::table.load::<BlobRecord>(&self.database.lock().await.connection)?;
+        let mut objects = records
+            .into_iter()
+            .map(|r| Object {
+                key: r.key,
+                size: r.size as u64,
+                modified: DateTime::from_utc(r.created_at, Utc),
+            })
+            .collect::<Vec<_>>();
+        objects.extend(not_blobs(self.inner.list().await?));
+
+        Ok(objects)
+    }
+}
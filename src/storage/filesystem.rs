@@ -0,0 +1,126 @@
+use super::{file_chunks, ByteStream, Object, Storage};
+use crate::config::Config;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use std::{
+    future::Future,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{fs, sync::RwLock, task};
+use walkdir::WalkDir;
+
+/// crate files under `crates.storage_path`
+pub(super) struct FsStorage {
+    config: Arc<RwLock<Config>>,
+}
+
+impl FsStorage {
+    pub(super) fn new(config: Arc<RwLock<Config>>) -> Self {
+        FsStorage { config }
+    }
+
+    async fn path(&self, key: &str) -> PathBuf {
+        self.config.read().await.crates.storage_path.join(key)
+    }
+
+    /// write to a temp file then rename, so the readers never see a half written file
+    async fn write<F, Fut>(&self, key: &str, write: F) -> Result<()>
+    where
+        F: FnOnce(PathBuf) -> Fut,
+        Fut: Future<Output = io::Result<()>>,
+    {
+        let path = self.path(key).await;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        let tmp = path.with_extension(format!("{:x}.tmp", rand::random::<u32>()));
+        let result = match write(tmp.clone()).await {
+            Ok(_) => fs::rename(&tmp, path).await,
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp).await;
+        }
+
+        Ok(result?)
+    }
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key).await).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<Option<ByteStream>> {
+        match fs::File::open(self.path(key).await).await {
+            Ok(file) => Ok(Some(file_chunks(file).err_into().boxed())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match fs::metadata(self.path(key).await).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        self.write(key, |tmp| async move { fs::write(tmp, data).await })
+            .await
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        self.write(
+            key,
+            |tmp| async move { fs::copy(path, tmp).await.map(|_| ()) },
+        )
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path(key).await).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<Object>> {
+        let root = self.config.read().await.crates.storage_path.clone();
+        Ok(task::spawn_blocking(move || {
+            WalkDir::new(&root)
+                .min_depth(2)
+                .max_depth(2)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter(|e| !e.file_name().to_string_lossy().ends_with(".tmp"))
+                .filter_map(|e| {
+                    let meta = e.metadata().ok()?;
+                    let key = e.path().strip_prefix(&root).ok()?;
+                    Some(Object {
+                        key: key
+                            .iter()
+                            .map(|s| s.to_string_lossy())
+                            .collect::<Vec<_>>()
+                            .join("/"),
+                        size: meta.len(),
+                        modified: meta.modified().ok()?.into(),
+                    })
+                })
+                .collect()
+        })
+        .await?)
+    }
+}
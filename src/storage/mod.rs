@@ -0,0 +1,93 @@
+mod content;
+mod filesystem;
+mod s3;
+
+use crate::{config::Config, database::Database};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use content::ContentAddressed;
+use filesystem::FsStorage;
+use futures::{stream::BoxStream, Stream};
+use s3::S3Storage;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{io, path::Path, sync::Arc};
+use tokio::{fs::File, io::AsyncReadExt, sync::RwLock};
+
+/// the size of the chunks a file is read in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// an object read in chunks
+pub type ByteStream = BoxStream<'static, Result<Vec<u8>>>;
+
+/// an object in the crate storage
+#[derive(Serialize, Debug)]
+pub struct Object {
+    /// eg. `serde/serde-1.0.0.crate`
+    pub key: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+/// where the crate files are stored, the keys are `/` separated relative paths
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// `None` if not found
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// like `get`, the object is not read into memory at once
+    async fn get_stream(&self, key: &str) -> Result<Option<ByteStream>>;
+    /// without reading the object
+    async fn exists(&self, key: &str) -> Result<bool>;
+    /// readers never see a partial object
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+    /// like `put`, the file is not read into memory at once
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()>;
+    async fn delete(&self, key: &str) -> Result<()>;
+    async fn list(&self) -> Result<Vec<Object>>;
+}
+
+/// the key of a crate file, eg. `serde/serde-1.0.0.crate`
+pub fn crate_key(name: &str, version: &str) -> String {
+    format!("{}/{}-{}.crate", name, name, version)
+}
+
+/// the content of a file in chunks
+fn file_chunks(file: File) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    futures::stream::try_unfold(file, |mut file| async move {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let n = file.read(&mut buf).await?;
+        buf.truncate(n);
+        Ok(if n == 0 { None } else { Some((buf, file)) })
+    })
+}
+
+/// the sha256 of a file in hex and its size, read in chunks
+async fn hash_file(path: &Path) -> Result<(String, u64)> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// the s3-compatible object storage if `crates.s3` is set, otherwise the local `crates.storage_path`,
+/// the crate files are stored by their sha256 in it
+pub async fn new(config: Arc<RwLock<Config>>) -> Result<Arc<dyn Storage>> {
+    let s3 = config.read().await.crates.s3.clone();
+    let inner: Box<dyn Storage> = match s3 {
+        Some(s3) => Box::new(S3Storage::new(s3)?),
+        None => Box::new(FsStorage::new(config.clone())),
+    };
+    let database = Database::connect(config).await?;
+    Ok(Arc::new(ContentAddressed::new(inner, database)))
+}
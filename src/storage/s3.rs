@@ -0,0 +1,524 @@
+use super::{file_chunks, hash_file, ByteStream, Object, Storage};
+use crate::config::S3;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::{
+    header::CONTENT_LENGTH, Body, Client, Method, RequestBuilder, Response, StatusCode, Url,
+};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::fs::File;
+
+#[cfg(test)]
+mod test {
+    use super::{authorization, uri_encode, S3Storage};
+    use crate::{config::S3, storage::Storage};
+    use futures::TryStreamExt;
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    fn decode(s: &str) -> String {
+        let mut decoded = Vec::new();
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            if b == b'%' {
+                let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+            } else {
+                decoded.push(b);
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    /// a request to the s3 stand-in, one per connection
+    async fn handle(mut socket: TcpStream, objects: Objects) {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        let head_end = loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+        let length = head
+            .lines()
+            .find_map(|l| {
+                l.to_lowercase()
+                    .strip_prefix("content-length:")
+                    .map(|v| v.trim().to_string())
+            })
+            .map_or(0, |v| v.parse().unwrap());
+        while request.len() < head_end + length {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        let body = request[head_end..].to_vec();
+
+        let mut parts = head.split_whitespace();
+        let (method, target) = (parts.next().unwrap(), parts.next().unwrap());
+        let (path, query) = match target.find('?') {
+            Some(i) => (&target[..i], Some(&target[i + 1..])),
+            None => (target, None),
+        };
+        let key = decode(path.trim_start_matches("/crates/"));
+        let (status, content) = {
+            let mut objects = objects.lock().unwrap();
+            match (method, query) {
+                ("GET", Some(_)) => {
+                    let mut xml = "<ListBucketResult><IsTruncated>false</IsTruncated>".to_string();
+                    for (key, data) in objects.iter() {
+                        xml.push_str(&format!(
+                            "<Contents><Key>{}</Key><Size>{}</Size>\
+                         <LastModified>2021-04-01T00:00:00.000Z</LastModified></Contents>",
+                            key,
+                            data.len()
+                        ));
+                    }
+                    xml.push_str("</ListBucketResult>");
+                    ("200 OK", xml.into_bytes())
+                }
+                ("PUT", _) => {
+                    objects.insert(key, body);
+                    ("200 OK", Vec::new())
+                }
+                ("DELETE", _) => {
+                    objects.remove(&key);
+                    ("204 No Content", Vec::new())
+                }
+                ("GET", _) | ("HEAD", _) => match objects.get(&key) {
+                    Some(data) if method == "GET" => ("200 OK", data.clone()),
+                    Some(_) => ("200 OK", Vec::new()),
+                    None => ("404 Not Found", Vec::new()),
+                },
+                _ => ("405 Method Not Allowed", Vec::new()),
+            }
+        };
+
+        let response = format!(
+            "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            status,
+            if method == "HEAD" { 0 } else { content.len() }
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        if method != "HEAD" {
+            socket.write_all(&content).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let objects = Objects::default();
+        let served = objects.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle(socket, served.clone()));
+            }
+        });
+
+        let storage = S3Storage::new(S3 {
+            endpoint,
+            bucket: "crates".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "minioadmin".to_string(),
+            secret_key: "minioadmin".to_string(),
+            prefix: "mirror/".to_string(),
+        })
+        .unwrap();
+
+        let key = "serde/serde-1.0.0+a.crate";
+        storage.put(key, b"serde".to_vec()).await.unwrap();
+        let path = std::env::temp_dir().join(format!("s3-{:x}.crate", rand::random::<u32>()));
+        std::fs::write(&path, vec![7u8; 100_000]).unwrap();
+        storage
+            .put_file("rand/rand-0.8.0.crate", &path)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key("mirror/serde/serde-1.0.0+a.crate"));
+
+        assert_eq!(storage.get(key).await.unwrap(), Some(b"serde".to_vec()));
+        let chunks = storage
+            .get_stream("rand/rand-0.8.0.crate")
+            .await
+            .unwrap()
+            .unwrap()
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(chunks, vec![7u8; 100_000]);
+        assert!(storage.exists(key).await.unwrap());
+
+        let mut listed = storage
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|o| (o.key, o.size))
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(
+            listed,
+            vec![
+                ("rand/rand-0.8.0.crate".to_string(), 100_000),
+                (key.to_string(), 5)
+            ]
+        );
+
+        storage.delete(key).await.unwrap();
+        assert_eq!(storage.get(key).await.unwrap(), None);
+        assert!(storage.get_stream(key).await.unwrap().is_none());
+        assert!(!storage.exists(key).await.unwrap());
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(
+            uri_encode("serde/serde-1.0.0+a.crate", false),
+            "serde/serde-1.0.0%2Ba.crate"
+        );
+        assert_eq!(uri_encode("mirror/", true), "mirror%2F");
+        assert_eq!(uri_encode("a b~_.", true), "a%20b~_.");
+    }
+
+    #[test]
+    fn test_authorization() {
+        let cfg = S3 {
+            endpoint: "http://127.0.0.1:9000".to_string(),
+            bucket: "crates".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "minioadmin".to_string(),
+            secret_key: "minioadmin".to_string(),
+            prefix: String::new(),
+        };
+
+        // the expected signatures are generated by botocore
+        assert_eq!(
+            authorization(
+                &cfg,
+                "PUT",
+                "/crates/serde/serde-1.0.0.crate",
+                "",
+                "127.0.0.1:9000",
+                "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                "20210401T000000Z"
+            ),
+            "AWS4-HMAC-SHA256 Credential=minioadmin/20210401/us-east-1/s3/aws4_request, \
+            SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+            Signature=4d6caa04457418fb93409662a4ebffa460d269c420f751946dadaf636e04df14"
+        );
+        assert_eq!(
+            authorization(
+                &cfg,
+                "GET",
+                "/crates",
+                "list-type=2&prefix=mirror%2F",
+                "127.0.0.1:9000",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                "20210401T000000Z"
+            ),
+            "AWS4-HMAC-SHA256 Credential=minioadmin/20210401/us-east-1/s3/aws4_request, \
+            SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+            Signature=c25880acefcaf73dbf3bf917321d4d7de9fab1c0311a58d012b41b7d06ab0ba9"
+        );
+    }
+}
+
+/// crate files in a s3-compatible object storage, eg. MinIO, with path-style requests
+/// signed by AWS Signature Version 4
+pub(super) struct S3Storage {
+    client: Client,
+    config: S3,
+    host: String,
+}
+
+/// percent-encode everything except the unreserved characters, `/` is kept unless `encode_slash`
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// the `Authorization` header, signs `host`, `x-amz-content-sha256` and `x-amz-date` headers
+fn authorization(
+    cfg: &S3,
+    method: &str,
+    path: &str,
+    query: &str,
+    host: &str,
+    payload_hash: &str,
+    amz_date: &str,
+) -> String {
+    const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, cfg.region);
+    let canonical_request = format!(
+        "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        method, path, query, host, payload_hash, amz_date, SIGNED_HEADERS, payload_hash
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        amz_date,
+        scope,
+        Sha256::digest(canonical_request.as_bytes())
+    );
+
+    let mut key = hmac_sha256(format!("AWS4{}", cfg.secret_key).as_bytes(), date);
+    for part in [cfg.region.as_str(), "s3", "aws4_request"].iter() {
+        key = hmac_sha256(&key, part);
+    }
+    let signature = hmac_sha256(&key, &string_to_sign)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        cfg.access_key, scope, SIGNED_HEADERS, signature
+    )
+}
+
+/// the text of the first `<tag>` in `xml`
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+impl S3Storage {
+    pub(super) fn new(config: S3) -> Result<Self> {
+        let url = Url::parse(&config.endpoint)?;
+        let mut host = url
+            .host_str()
+            .ok_or(anyhow!("no host in s3 endpoint {}", config.endpoint))?
+            .to_string();
+        if let Some(port) = url.port() {
+            host = format!("{}:{}", host, port);
+        }
+
+        Ok(S3Storage {
+            client: Client::new(),
+            config,
+            host,
+        })
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: Vec<(&str, String)>,
+        body: Vec<u8>,
+    ) -> Result<Response> {
+        let payload_hash = format!("{:x}", Sha256::digest(&body));
+        Ok(self
+            .request(method, key, query, payload_hash)
+            .body(body)
+            .send()
+            .await?)
+    }
+
+    /// a signed request, the body of it must have the sha256 `payload_hash`
+    fn request(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        mut query: Vec<(&str, String)>,
+        payload_hash: String,
+    ) -> RequestBuilder {
+        let mut path = format!("/{}", uri_encode(&self.config.bucket, true));
+        if let Some(key) = key {
+            path.push('/');
+            path.push_str(&uri_encode(
+                &format!("{}{}", self.config.prefix, key),
+                false,
+            ));
+        }
+
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, true), uri_encode(v, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let auth = authorization(
+            &self.config,
+            method.as_str(),
+            &path,
+            &query,
+            &self.host,
+            &payload_hash,
+            &amz_date,
+        );
+
+        let mut url = format!("{}{}", self.config.endpoint.trim_end_matches('/'), path);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+
+        self.client
+            .request(method, &url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("Authorization", auth)
+    }
+}
+
+async fn check(response: Response) -> Result<Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    bail!("s3 return {}: {}", status, body)
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.send(Method::GET, Some(key), vec![], vec![]).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(check(response).await?.bytes().await?.to_vec()))
+    }
+
+    /// the body of the response in chunks
+    async fn get_stream(&self, key: &str) -> Result<Option<ByteStream>> {
+        let response = self.send(Method::GET, Some(key), vec![], vec![]).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            check(response)
+                .await?
+                .bytes_stream()
+                .map_ok(|chunk| chunk.to_vec())
+                .err_into()
+                .boxed(),
+        ))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let response = self.send(Method::HEAD, Some(key), vec![], vec![]).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+
+        check(response).await?;
+        Ok(true)
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        check(self.send(Method::PUT, Some(key), vec![], data).await?).await?;
+        Ok(())
+    }
+
+    /// the file is hashed for the signature first, then streamed in the request
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        let (payload_hash, size) = hash_file(path).await?;
+        let chunks = file_chunks(File::open(path).await?);
+        let response = self
+            .request(Method::PUT, Some(key), vec![], payload_hash)
+            .header(CONTENT_LENGTH, size)
+            .body(Body::wrap_stream(chunks))
+            .send()
+            .await?;
+        check(response).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        check(self.send(Method::DELETE, Some(key), vec![], vec![]).await?).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut token = None;
+        loop {
+            let mut query = vec![
+                ("list-type", "2".to_string()),
+                ("prefix", self.config.prefix.clone()),
+            ];
+            if let Some(t) = token.take() {
+                query.push(("continuation-token", t));
+            }
+
+            let xml = check(self.send(Method::GET, None, query, vec![]).await?)
+                .await?
+                .text()
+                .await?;
+            for contents in xml.split("<Contents>").skip(1) {
+                let key = unescape(tag(contents, "Key").unwrap_or_default());
+                objects.push(Object {
+                    key: key
+                        .strip_prefix(&self.config.prefix)
+                        .unwrap_or(&key)
+                        .to_string(),
+                    size: tag(contents, "Size")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or_default(),
+                    modified: tag(contents, "LastModified")
+                        .and_then(|m| DateTime::parse_from_rfc3339(m).ok())
+                        .map(|m| m.with_timezone(&Utc))
+                        .unwrap_or_else(Utc::now),
+                });
+            }
+
+            match tag(&xml, "NextContinuationToken") {
+                Some(t) if tag(&xml, "IsTruncated") == Some("true") => token = Some(unescape(t)),
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+}
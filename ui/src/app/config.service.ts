@@ -18,6 +18,7 @@ export class GitConfig {
   index_path: string;
   working_path: string;
   upstream_url: string;
+  upstream_sparse_url: string;
 }
 
 export class CratesConfig {
@@ -28,6 +29,8 @@ export class CratesConfig {
 export class RegistryConfig {
   address: string;
   interval: string;
+  auth_required: boolean;
+  webhook_token: string;
   can_create_account: boolean;
   ldap: Ldap;
 }
@@ -49,9 +52,9 @@ export class DatabaseConfig {
 })
 export class ConfigService {
   current: Config = {
-    git: { index_path: '', working_path: '', upstream_url: '' },
+    git: { index_path: '', working_path: '', upstream_url: '', upstream_sparse_url: '' },
     registry: {
-      address: '', interval: '', can_create_account: false,
+      address: '', interval: '', auth_required: false, webhook_token: '', can_create_account: false,
       ldap: { hostname: '', base_dn: '', username: '', password: '', domain: '' },
     },
     crates: { storage_path: '', upstream_url: '' },